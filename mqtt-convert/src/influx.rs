@@ -0,0 +1,191 @@
+use serde_json::{Map, Value};
+use std::io::Write;
+
+/// Writes numeric payloads as InfluxDB line protocol.
+///
+/// A payload is either a single number, written as the field `value`, or a flat JSON object
+/// of numbers, written as one field per key. The first topic levels become the tags `level1`,
+/// `level2`, .. and the rest of the topic the measurement, e.g. `home/kitchen/temperature`
+/// with a depth of 2 is `temperature,level1=home,level2=kitchen value=21.5`.
+pub struct InfluxWriter<W: Write> {
+    inner: W,
+    /// Written instead of the topic, which is then kept in the `topic` tag
+    measurement: Option<String>,
+    tag_depth: usize,
+}
+
+impl<W: Write> InfluxWriter<W> {
+    pub fn new(inner: W, measurement: Option<String>, tag_depth: usize) -> Self {
+        InfluxWriter {
+            inner,
+            measurement,
+            tag_depth,
+        }
+    }
+
+    /// Writes a point, returning false if the payload is not numeric.
+    pub fn write(&mut self, secs: f64, topic: &str, payload: &[u8]) -> anyhow::Result<bool> {
+        let fields = match numeric_fields(payload) {
+            Some(fields) => fields,
+            None => return Ok(false),
+        };
+
+        let levels: Vec<_> = topic.split('/').collect();
+        // The last level is always part of the measurement, empty trailing levels as of `a/` are
+        // not one, or the measurement would be empty
+        let last = levels.iter().rposition(|level| !level.is_empty());
+        let depth = self.tag_depth.min(last.unwrap_or(0));
+
+        let mut line = match &self.measurement {
+            Some(measurement) => format!(
+                "{},topic={}",
+                escape(measurement, ", "),
+                escape(topic, ",= ")
+            ),
+            None => escape(&levels[depth..].join("/"), ", "),
+        };
+
+        for (i, level) in levels[..depth].iter().enumerate() {
+            // Empty tag values are not allowed
+            if !level.is_empty() {
+                line += &format!(",level{}={}", i + 1, escape(level, ",= "));
+            }
+        }
+
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", escape(key, ",= "), value))
+            .collect();
+
+        writeln!(
+            self.inner,
+            "{} {} {}",
+            line,
+            fields.join(","),
+            (secs * 1e6).round() as i64 * 1000
+        )?;
+
+        Ok(true)
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+/// The fields of a number or a flat JSON object of numbers, all written as floats so a field
+/// keeps one type in InfluxDB.
+fn numeric_fields(payload: &[u8]) -> Option<Vec<(String, f64)>> {
+    let text = std::str::from_utf8(payload).ok()?.trim();
+
+    if let Ok(value) = text.parse::<f64>() {
+        return value
+            .is_finite()
+            .then(|| vec![("value".to_string(), value)]);
+    }
+
+    let object: Map<String, Value> = serde_json::from_str(text).ok()?;
+    if object.is_empty() {
+        return None;
+    }
+
+    object
+        .into_iter()
+        .map(|(key, value)| Some((key, value.as_f64()?)))
+        .collect()
+}
+
+fn escape(s: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(measurement: Option<&str>, tag_depth: usize, topic: &str, payload: &str) -> String {
+        let mut writer = InfluxWriter::new(Vec::new(), measurement.map(String::from), tag_depth);
+        assert!(writer.write(1.5, topic, payload.as_bytes()).unwrap());
+
+        String::from_utf8(writer.inner).unwrap()
+    }
+
+    #[test]
+    fn number_payloads() {
+        assert_eq!(line(None, 0, "a/b", "21.5"), "a/b value=21.5 1500000000\n");
+        assert_eq!(line(None, 0, "a/b", " -3\n"), "a/b value=-3 1500000000\n");
+    }
+
+    #[test]
+    fn object_payloads() {
+        assert_eq!(
+            line(None, 0, "a", r#"{"t": 21.5, "rh": 40}"#),
+            "a rh=40,t=21.5 1500000000\n"
+        );
+    }
+
+    #[test]
+    fn non_numeric_payloads() {
+        let mut writer = InfluxWriter::new(Vec::new(), None, 0);
+        for payload in [
+            "on",
+            "",
+            "NaN",
+            "inf",
+            "{}",
+            r#"{"a": "b"}"#,
+            r#"{"a": {"b": 1}}"#,
+            "[1]",
+        ] {
+            assert!(!writer.write(1., "a", payload.as_bytes()).unwrap());
+        }
+        assert!(!writer.write(1., "a", &[0xff, 0x31]).unwrap());
+
+        assert!(writer.inner.is_empty());
+    }
+
+    #[test]
+    fn topic_levels_as_tags() {
+        assert_eq!(
+            line(None, 2, "home/kitchen/temperature", "21.5"),
+            "temperature,level1=home,level2=kitchen value=21.5 1500000000\n"
+        );
+        assert_eq!(line(None, 5, "a/b", "1"), "b,level1=a value=1 1500000000\n");
+        assert_eq!(line(None, 1, "/a", "1"), "a value=1 1500000000\n");
+        assert_eq!(
+            line(Some("m"), 1, "a/b", "1"),
+            "m,topic=a/b,level1=a value=1 1500000000\n"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            line(None, 1, "a b,c=d/e f,g=h", r#"{"x y,z=": 1}"#),
+            "e\\ f\\,g=h,level1=a\\ b\\,c\\=d x\\ y\\,z\\==1 1500000000\n"
+        );
+        assert_eq!(
+            line(Some("m n,o"), 0, "a b,c=d", "1"),
+            "m\\ n\\,o,topic=a\\ b\\,c\\=d value=1 1500000000\n"
+        );
+        assert_eq!(line(None, 0, "a\\b", "1"), "a\\\\b value=1 1500000000\n");
+    }
+
+    #[test]
+    fn empty_trailing_levels_stay_in_the_measurement() {
+        assert_eq!(line(None, 1, "a/", "1"), "a/ value=1 1500000000\n");
+        assert_eq!(
+            line(None, 2, "a/b/", "1"),
+            "b/,level1=a value=1 1500000000\n"
+        );
+        assert_eq!(line(None, 1, "/", "1"), "/ value=1 1500000000\n");
+    }
+}
@@ -0,0 +1,304 @@
+use anyhow::anyhow;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::{LogReader, ReadError};
+use simple_logger::SimpleLogger;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+mod influx;
+mod sqlite;
+
+use influx::InfluxWriter;
+use sqlite::SqliteWriter;
+
+/// What the log is converted to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    /// A `messages` table in an SQLite database, with the payloads as blobs
+    Sqlite,
+    /// InfluxDB line protocol of the numeric payloads
+    Influx,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "sqlite" => Ok(Format::Sqlite),
+            "influx" => Ok(Format::Influx),
+            _ => Err(anyhow!(
+                "Unknown format '{}', expected csv, sqlite or influx",
+                s
+            )),
+        }
+    }
+}
+
+/// How payloads are written to CSV.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decode {
+    /// Base64 of the raw bytes, in the `msg_b64` column
+    Base64,
+    /// UTF-8 text, in the `payload` column
+    Text,
+}
+
+impl FromStr for Decode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(Decode::Base64),
+            "text" => Ok(Decode::Text),
+            _ => Err(anyhow!("Unknown decoding '{}', expected base64 or text", s)),
+        }
+    }
+}
+
+enum Output {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Sqlite(SqliteWriter),
+    Influx(InfluxWriter<Box<dyn Write>>),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-convert",
+    about = "Converts a logged MQTT stream to CSV, SQLite or InfluxDB line protocol"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Input log file
+    #[structopt(env = "INPUT", parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output file, stdout is used for CSV and InfluxDB if not given
+    #[structopt(short, long, env = "OUTPUT", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Output format: csv, sqlite to insert into the `messages` table of a database, which
+    /// is created if needed, or influx for InfluxDB line protocol of the messages with a
+    /// number or a flat JSON object of numbers as payload
+    #[structopt(long, env = "FORMAT", default_value = "csv")]
+    format: Format,
+
+    /// InfluxDB measurement of all points, the topic is then kept in the `topic` tag. The
+    /// topic levels after --tag-from-topic-depth are the measurement if not given
+    #[structopt(long, env = "MEASUREMENT")]
+    measurement: Option<String>,
+
+    /// Number of leading topic levels written as the InfluxDB tags `level1`, `level2`, ..
+    #[structopt(long, env = "TAG_FROM_TOPIC_DEPTH", default_value = "0")]
+    tag_from_topic_depth: usize,
+
+    /// How payloads are written to CSV: base64, or text for payloads as plain UTF-8 in the
+    /// `payload` column. Payloads which are not UTF-8 are then left empty and counted
+    #[structopt(long, env = "DECODE", default_value = "base64")]
+    decode: Decode,
+
+    /// Write the time column as ISO-8601 (UTC) instead of seconds since the Unix epoch
+    #[structopt(long)]
+    iso_time: bool,
+
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    let input = opt.input;
+    let codec = Codec::of_input(&input, opt.zstd);
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    let log_file = LogReader::new(BufReader::new(codec.decoder(File::open(&input)?)?))?;
+
+    // The text formats are written to stdout without an output file
+    let output_path = &opt.output;
+    let text_output = || -> anyhow::Result<Box<dyn Write>> {
+        Ok(match output_path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(io::stdout())),
+        })
+    };
+
+    let mut output = match opt.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(text_output()?);
+
+            let payload_column = match opt.decode {
+                Decode::Base64 => "msg_b64",
+                Decode::Text => "payload",
+            };
+            writer.write_record([
+                "time",
+                "qos",
+                "retain",
+                "topic",
+                "payload_len",
+                payload_column,
+            ])?;
+
+            Output::Csv(Box::new(writer))
+        }
+        Format::Sqlite => match &opt.output {
+            Some(path) => Output::Sqlite(SqliteWriter::create(path)?),
+            None => {
+                return Err(anyhow!(
+                    "An --output database is needed with --format sqlite"
+                ))
+            }
+        },
+        Format::Influx => Output::Influx(InfluxWriter::new(
+            text_output()?,
+            opt.measurement.clone(),
+            opt.tag_from_topic_depth,
+        )),
+    };
+
+    let mut count: u64 = 0;
+    let mut malformed: u64 = 0;
+    let mut not_numeric: u64 = 0;
+    let mut not_text: u64 = 0;
+
+    for msg in log_file {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(ReadError::Corrupt(e)) => {
+                error!("Corrupted dataset: {}", e);
+                continue;
+            }
+            Err(ReadError::Io(e)) => {
+                error!(
+                    "Unable to read further from the log file, it may be truncated: {}",
+                    e
+                );
+                break;
+            }
+        };
+
+        // Payloads which do not decode are written as empty, or NULL in SQLite, and counted
+        let payload = match msg.payload.decode() {
+            Ok(payload) => Some(payload),
+            Err(e) => {
+                debug!(
+                    "Payload on topic '{}' could not be decoded: {}",
+                    msg.topic, e
+                );
+                malformed += 1;
+                None
+            }
+        };
+
+        let secs = msg.time.secs();
+        match &mut output {
+            Output::Csv(writer) => {
+                let time = if opt.iso_time {
+                    let whole = secs.floor();
+                    let nanos = ((secs - whole) * 1e9) as u32;
+                    match Utc.timestamp_opt(whole as i64, nanos).single() {
+                        Some(time) => time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                        None => secs.to_string(),
+                    }
+                } else {
+                    secs.to_string()
+                };
+
+                let (payload_len, payload) = match &payload {
+                    Some(payload) => {
+                        let payload_column = match opt.decode {
+                            Decode::Base64 => base64::encode(payload),
+                            Decode::Text => match std::str::from_utf8(payload) {
+                                Ok(text) => text.to_string(),
+                                Err(e) => {
+                                    debug!(
+                                        "Payload on topic '{}' is not UTF-8 text: {}",
+                                        msg.topic, e
+                                    );
+                                    not_text += 1;
+                                    String::new()
+                                }
+                            },
+                        };
+                        (payload.len().to_string(), payload_column)
+                    }
+                    None => (String::new(), String::new()),
+                };
+
+                writer.write_record(&[
+                    time,
+                    msg.qos.to_string(),
+                    msg.retain.to_string(),
+                    msg.topic,
+                    payload_len,
+                    payload,
+                ])?;
+            }
+            Output::Sqlite(db) => {
+                db.insert(secs, &msg.topic, msg.qos, msg.retain, payload.as_deref())?;
+            }
+            Output::Influx(writer) => {
+                let written = match &payload {
+                    Some(payload) => writer.write(secs, &msg.topic, payload)?,
+                    None => false,
+                };
+                if !written {
+                    trace!("Skipped non-numeric payload on topic '{}'", msg.topic);
+                    not_numeric += 1;
+                    continue;
+                }
+            }
+        }
+
+        count += 1;
+    }
+
+    match output {
+        Output::Csv(mut writer) => writer.flush()?,
+        Output::Sqlite(db) => db.finish()?,
+        Output::Influx(mut writer) => writer.flush()?,
+    }
+
+    eprintln!("Converted {} messages", count);
+
+    if malformed > 0 {
+        eprintln!("{} messages had a malformed payload", malformed);
+    }
+
+    if not_text > 0 {
+        eprintln!(
+            "{} messages had a payload which is not UTF-8 text and was left empty",
+            not_text
+        );
+    }
+
+    if not_numeric > 0 {
+        eprintln!(
+            "{} messages had no numeric payload and were skipped",
+            not_numeric
+        );
+    }
+
+    Ok(())
+}
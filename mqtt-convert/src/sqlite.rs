@@ -0,0 +1,66 @@
+use anyhow::Context;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+/// Rows inserted per transaction, committing every row is slow.
+const BATCH_SIZE: usize = 1000;
+
+/// Inserts messages into the `messages` table of an SQLite database.
+pub struct SqliteWriter {
+    conn: Connection,
+    pending: usize,
+}
+
+impl SqliteWriter {
+    /// Opens or creates the database, messages are added to an existing table.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Could not open database '{}'", path.display()))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 time REAL NOT NULL,
+                 topic TEXT NOT NULL,
+                 qos INTEGER NOT NULL,
+                 retain INTEGER NOT NULL,
+                 payload BLOB
+             );
+             CREATE INDEX IF NOT EXISTS messages_topic ON messages (topic);
+             CREATE INDEX IF NOT EXISTS messages_time ON messages (time);
+             BEGIN;",
+        )?;
+
+        Ok(SqliteWriter { conn, pending: 0 })
+    }
+
+    /// Inserts a message, a payload which could not be decoded is stored as NULL.
+    pub fn insert(
+        &mut self,
+        time: f64,
+        topic: &str,
+        qos: u8,
+        retain: bool,
+        payload: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT INTO messages (time, topic, qos, retain, payload) VALUES (?, ?, ?, ?, ?)",
+            )?
+            .execute(params![time, topic, qos, retain, payload])?;
+
+        self.pending += 1;
+        if self.pending >= BATCH_SIZE {
+            self.conn.execute_batch("COMMIT; BEGIN;")?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the remaining messages.
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.conn.execute_batch("COMMIT;")?;
+
+        Ok(())
+    }
+}
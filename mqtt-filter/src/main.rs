@@ -0,0 +1,368 @@
+use anyhow::anyhow;
+use chrono::DateTime;
+use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::index::{read_index, seek_frame};
+use mqtt_logger::reader::{LogReader, ReadError};
+use mqtt_logger::topic_matches;
+use mqtt_logger::writer::Format;
+use simple_logger::SimpleLogger;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-filter",
+    about = "Writes the messages of a logged MQTT stream on selected topics to a new log"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Input log file
+    #[structopt(env = "INPUT", parse(from_os_str))]
+    input: PathBuf,
+
+    /// Output log file, in the format of the input and compressed by its extension: .zst, .gz
+    /// or .lz4
+    #[structopt(short, long, env = "OUTPUT", parse(from_os_str))]
+    output: PathBuf,
+
+    /// Topic filter of messages to keep, e.g. 'sensors/+/temperature'. Uses MQTT wildcards and
+    /// supports multiple, all messages are kept if not given
+    #[structopt(long, env = "INCLUDE")]
+    include: Vec<String>,
+
+    /// Topic filter of messages to drop, e.g. '$SYS/#'. Uses MQTT wildcards and supports multiple
+    #[structopt(long, env = "EXCLUDE")]
+    exclude: Vec<String>,
+
+    /// Drop messages before this time, as seconds since the Unix epoch or in ISO 8601, e.g.
+    /// 2021-01-20T10:15:00Z
+    #[structopt(long, env = "SINCE", parse(try_from_str = parse_time))]
+    since: Option<f64>,
+
+    /// Drop messages after this time, as seconds since the Unix epoch or in ISO 8601. Reading
+    /// stops at the first later message, as logs are ordered by time
+    #[structopt(long, env = "UNTIL", parse(try_from_str = parse_time))]
+    until: Option<f64>,
+
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+
+    /// The compression level of the filtered log file
+    #[structopt(long, env = "COMPRESSION_LEVEL", default_value = "9")]
+    compression_level: i32,
+}
+
+/// Seconds since the Unix epoch, from seconds or an ISO 8601 timestamp with a time zone.
+fn parse_time(s: &str) -> anyhow::Result<f64> {
+    if let Ok(secs) = s.parse() {
+        return Ok(secs);
+    }
+
+    let time = DateTime::parse_from_rfc3339(s).map_err(|e| {
+        anyhow!(
+            "Invalid time '{}', expected seconds since the Unix epoch or ISO 8601: {}",
+            s,
+            e
+        )
+    })?;
+
+    Ok(time.timestamp_micros() as f64 / 1e6)
+}
+
+/// The messages read and kept by filtering a log.
+struct Filtered {
+    /// Messages before the frame reading started at, none if the index can not tell
+    skipped: Option<u64>,
+    /// Messages read, up to the end of the time window
+    read: u64,
+    written: u64,
+    /// Whether reading stopped at a message after --until
+    window_ended: bool,
+}
+
+/// Writes the selected messages of the input log to the output log.
+fn filter(opt: &Opt) -> anyhow::Result<Filtered> {
+    let input = &opt.input;
+    let output = &opt.output;
+    let codec = Codec::of_input(input, opt.zstd);
+
+    let mut log_file = File::open(input)?;
+    let mut skipped = Some(0);
+    // The index of a log with frames tells where to start reading
+    if let (Some(since), Some(index)) = (opt.since, read_index(input)?) {
+        if let Some(frame) = seek_frame(&index, since) {
+            debug!("Starting to read at the frame at {} bytes", frame.offset);
+            log_file.seek(SeekFrom::Start(frame.offset))?;
+
+            // The messages are counted from 0 again by each logging session appending to the log
+            let appended = index[1..]
+                .iter()
+                .take_while(|entry| entry.offset <= frame.offset)
+                .any(|entry| entry.messages == 0);
+            skipped = (!appended).then_some(frame.messages);
+        }
+    }
+    let log_file = LogReader::new(BufReader::new(codec.decoder(log_file)?))?;
+    let format = match log_file {
+        LogReader::Json(..) => Format::Json,
+        LogReader::MsgPack(_) => Format::MsgPack,
+    };
+    let output_codec = Codec::from_path(output);
+
+    // Dropping the writer finishes the compressed stream
+    let mut writer = format.writer(
+        output_codec.encoder(
+            BufWriter::new(File::create(output)?),
+            opt.compression_level,
+            1,
+        )?,
+        false,
+    )?;
+
+    let mut read = 0u64;
+    let mut written = 0u64;
+    let mut window_ended = false;
+
+    for msg in log_file {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(ReadError::Corrupt(e)) => {
+                error!("Corrupted dataset: {}", e);
+                continue;
+            }
+            Err(ReadError::Io(e)) => {
+                error!(
+                    "Unable to read further from the log file, it may be truncated: {}",
+                    e
+                );
+                break;
+            }
+        };
+        let time = msg.time.secs();
+        if opt.until.is_some_and(|until| time > until) {
+            debug!("Reached the end of the time window at {}", time);
+            window_ended = true;
+            break;
+        }
+        read += 1;
+
+        if opt.since.is_some_and(|since| time < since) {
+            continue;
+        }
+
+        let included = opt.include.is_empty()
+            || opt
+                .include
+                .iter()
+                .any(|filter| topic_matches(filter, &msg.topic));
+        let excluded = opt
+            .exclude
+            .iter()
+            .any(|filter| topic_matches(filter, &msg.topic));
+        if !included || excluded {
+            trace!("Filtered out message on topic '{}'", msg.topic);
+            continue;
+        }
+
+        writer.write_message(&msg)?;
+        written += 1;
+    }
+
+    writer.flush()?;
+    drop(writer);
+
+    Ok(Filtered {
+        skipped,
+        read,
+        written,
+        window_ended,
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    if let (Some(since), Some(until)) = (opt.since, opt.until) {
+        if since > until {
+            return Err(anyhow!("--since needs to be before --until"));
+        }
+    }
+
+    let input = &opt.input;
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    if opt.output.exists() && opt.output.canonicalize()? == input.canonicalize()? {
+        return Err(anyhow!(
+            "The filtered log must be written to another file than '{}'",
+            input.display()
+        ));
+    }
+
+    let filtered = filter(&opt)?;
+
+    match filtered.skipped {
+        Some(skipped) => println!(
+            "Kept {} of {} messages, written to '{}'",
+            filtered.written,
+            skipped + filtered.read,
+            opt.output.display()
+        ),
+        None => println!(
+            "Kept {} of {} messages read from the frame of --since on, written to '{}'",
+            filtered.written,
+            filtered.read,
+            opt.output.display()
+        ),
+    }
+    if filtered.window_ended {
+        println!("Stopped reading at the end of the time window");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mqtt_logger::index::{index_path, IndexEntry, IndexWriter};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mqtt-filter-{}-{}", std::process::id(), name))
+    }
+
+    /// Writes a log of a message a second from 0 to 29, in ZSTD frames of 10 messages with an
+    /// index. The index counts the messages from 0 at `restart`, like an appended log.
+    fn write_log(path: &Path, restart: Option<u64>) {
+        File::create(path).unwrap();
+        let mut index = IndexWriter::open(path, false).unwrap();
+
+        for start in [0, 10, 20] {
+            index
+                .add(&IndexEntry {
+                    offset: fs::metadata(path).unwrap().len(),
+                    time: start as f64,
+                    messages: start - restart.filter(|&r| r <= start).unwrap_or(0),
+                })
+                .unwrap();
+
+            let file = OpenOptions::new().append(true).open(path).unwrap();
+            let mut encoder = Codec::Zstd.encoder(file, 3, 1).unwrap();
+            for time in start..start + 10 {
+                writeln!(
+                    encoder,
+                    r#"{{"time":{}.0,"qos":0,"retain":false,"topic":"a/b","msg":""}}"#,
+                    time
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// Filters a log written by `write_log`, returning the result and the times kept.
+    fn run(name: &str, restart: Option<u64>, index: bool, args: &[&str]) -> (Filtered, Vec<f64>) {
+        let input = temp_path(&format!("{}.json.zst", name));
+        let output = temp_path(&format!("{}-filtered.json.zst", name));
+        write_log(&input, restart);
+        if !index {
+            fs::remove_file(index_path(&input)).unwrap();
+        }
+
+        let opt = Opt::from_iter_safe(
+            [
+                "mqtt-filter",
+                input.to_str().unwrap(),
+                "-o",
+                output.to_str().unwrap(),
+            ]
+            .iter()
+            .chain(args),
+        )
+        .unwrap();
+        let filtered = filter(&opt).unwrap();
+
+        let decoder = Codec::Zstd.decoder(File::open(&output).unwrap()).unwrap();
+        let times = LogReader::new(BufReader::new(decoder))
+            .unwrap()
+            .map(|msg| msg.unwrap().time.secs())
+            .collect();
+        fs::remove_file(&input).unwrap();
+        let _ = fs::remove_file(index_path(&input));
+        fs::remove_file(&output).unwrap();
+
+        (filtered, times)
+    }
+
+    fn range(times: std::ops::RangeInclusive<u32>) -> Vec<f64> {
+        times.map(f64::from).collect()
+    }
+
+    #[test]
+    fn time_window_seeking_by_the_index() {
+        let (filtered, times) = run("index", None, true, &["--since", "12", "--until", "25"]);
+
+        assert_eq!(times, range(12..=25));
+        assert_eq!(filtered.written, 14);
+        // The messages of the frame before were not read, but counted by the index
+        assert_eq!(filtered.skipped, Some(10));
+        assert_eq!(filtered.read, 16);
+        assert!(filtered.window_ended);
+    }
+
+    #[test]
+    fn time_window_without_an_index() {
+        let (filtered, times) = run("no-index", None, false, &["--since", "12", "--until", "25"]);
+
+        assert_eq!(times, range(12..=25));
+        assert_eq!(filtered.skipped, Some(0));
+        assert_eq!(filtered.read, 26);
+        assert!(filtered.window_ended);
+    }
+
+    #[test]
+    fn window_to_the_end_of_the_log() {
+        let (filtered, times) = run("to-end", None, true, &["--since", "2021-01-01T00:00:00Z"]);
+
+        assert!(times.is_empty());
+        assert_eq!(filtered.skipped, Some(20));
+        assert_eq!(filtered.read, 10);
+        assert!(!filtered.window_ended);
+
+        let (filtered, times) = run("until", None, true, &["--until", "4.5"]);
+
+        assert_eq!(times, range(0..=4));
+        assert_eq!(filtered.read, 5);
+        assert!(filtered.window_ended);
+    }
+
+    #[test]
+    fn appended_index_counts_are_not_summed() {
+        let (filtered, times) = run("appended", Some(20), true, &["--since", "22"]);
+
+        assert_eq!(times, range(22..=29));
+        assert_eq!(filtered.skipped, None);
+        assert_eq!(filtered.read, 10);
+
+        // Frames before the appended session are counted
+        let (filtered, _) = run("before-append", Some(20), true, &["--since", "12"]);
+        assert_eq!(filtered.skipped, Some(10));
+    }
+}
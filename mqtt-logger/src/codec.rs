@@ -0,0 +1,278 @@
+use anyhow::anyhow;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Compression of the log file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+    Lz4,
+    None,
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            "lz4" => Ok(Codec::Lz4),
+            "none" => Ok(Codec::None),
+            _ => Err(anyhow!(
+                "Unknown codec '{}', expected zstd, gzip, lz4 or none",
+                s
+            )),
+        }
+    }
+}
+
+impl Codec {
+    /// The extension added after the format extension, if any.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Codec::Zstd => Some("zst"),
+            Codec::Gzip => Some("gz"),
+            Codec::Lz4 => Some("lz4"),
+            Codec::None => None,
+        }
+    }
+
+    /// The codec of a log file by its extension, e.g. `.zst`, without one it is uncompressed.
+    pub fn from_path(path: &Path) -> Codec {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("zst") => Codec::Zstd,
+            Some("gz") => Codec::Gzip,
+            Some("lz4") => Codec::Lz4,
+            _ => Codec::None,
+        }
+    }
+
+    /// The codec of a log file read by the tools, by its extension unless their `--zstd` option
+    /// is given, which says whether it is ZSTD compressed or uncompressed.
+    pub fn of_input(path: &Path, zstd: Option<bool>) -> Codec {
+        match zstd {
+            Some(true) => Codec::Zstd,
+            Some(false) => Codec::None,
+            None => Codec::from_path(path),
+        }
+    }
+
+    /// Whether the codec uses the compression level.
+    pub fn has_level(&self) -> bool {
+        self.level_range().is_some()
+    }
+
+    /// The valid compression levels, if the codec uses them.
+    pub fn level_range(&self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Codec::Zstd => Some(zstd::compression_level_range()),
+            Codec::Gzip => Some(0..=9),
+            Codec::Lz4 | Codec::None => None,
+        }
+    }
+
+    /// Wraps `inner` in an encoder, the stream is finished when it is dropped. More than one
+    /// thread compresses on background threads, only zstd uses them.
+    pub fn encoder<W: Write + 'static>(
+        &self,
+        inner: W,
+        level: i32,
+        threads: u32,
+    ) -> anyhow::Result<Box<dyn Write>> {
+        Ok(match self {
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(inner, level)?;
+                if threads > 1 {
+                    encoder.multithread(threads)?;
+                }
+                Box::new(ZstdEncoder(encoder.auto_finish()))
+            }
+            Codec::Gzip => Box::new(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::new(level as u32),
+            )),
+            Codec::Lz4 => Box::new(Lz4Encoder(Some(lz4_flex::frame::FrameEncoder::new(inner)))),
+            Codec::None => Box::new(inner),
+        })
+    }
+
+    /// Wraps `inner` in a decoder which reads all concatenated streams.
+    pub fn decoder<R: Read + Send + 'static>(
+        &self,
+        inner: R,
+    ) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Codec::Zstd => Box::new(zstd::Decoder::new(inner)?),
+            Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(inner)),
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(inner)),
+            Codec::None => Box::new(inner),
+        })
+    }
+}
+
+// The zstd and lz4 encoders only flush their own buffers, these also flush the writer below so
+// flushed data reaches the file.
+
+struct ZstdEncoder<W: Write>(zstd::stream::AutoFinishEncoder<'static, W>);
+
+impl<W: Write> Write for ZstdEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_mut().flush()
+    }
+}
+
+/// Finishes the frame when dropped.
+struct Lz4Encoder<W: Write>(Option<lz4_flex::frame::FrameEncoder<W>>);
+
+impl<W: Write> Write for Lz4Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .as_mut()
+            .expect("Encoder is only taken on drop")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let encoder = self.0.as_mut().expect("Encoder is only taken on drop");
+        encoder.flush()?;
+        encoder.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for Lz4Encoder<W> {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.0.take() {
+            // Errors can not be reported from here, like with the other auto-finishing encoders
+            let _ = encoder.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    #[test]
+    fn codec_of_written_extension() {
+        for codec in [Codec::Zstd, Codec::Gzip, Codec::Lz4, Codec::None] {
+            let path = match codec.extension() {
+                Some(extension) => format!("log.json.{}", extension),
+                None => "log.json".to_string(),
+            };
+
+            assert_eq!(Codec::from_path(Path::new(&path)), codec);
+            assert_eq!(Codec::of_input(Path::new(&path), None), codec);
+        }
+
+        assert_eq!(Codec::of_input(Path::new("log"), Some(true)), Codec::Zstd);
+        assert_eq!(
+            Codec::of_input(Path::new("log.json.zst"), Some(false)),
+            Codec::None
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Log lines of `len` bytes or a little more, varied enough not to compress to nothing.
+    fn log_lines(len: usize) -> Vec<u8> {
+        let mut lines = Vec::with_capacity(len + 200);
+        let mut i = 0u64;
+        while lines.len() < len {
+            let value = i.wrapping_mul(6364136223846793005) >> 40;
+            writeln!(
+                lines,
+                r#"{{"time":{}.{:06},"qos":0,"retain":false,"topic":"sensors/{}/value","msg":"{}"}}"#,
+                1_600_000_000 + i / 100,
+                i % 1_000_000,
+                i % 37,
+                value
+            )
+            .unwrap();
+            i += 1;
+        }
+
+        lines
+    }
+
+    /// Compresses `data` with zstd, returning the compressed stream.
+    fn compress(data: &[u8], level: i32, threads: u32) -> Vec<u8> {
+        let sink = SharedSink::default();
+        let mut encoder = Codec::Zstd.encoder(sink.clone(), level, threads).unwrap();
+        // Written in pieces like log lines, not as one buffer
+        for chunk in data.chunks(100) {
+            encoder.write_all(chunk).unwrap();
+        }
+        drop(encoder);
+
+        let compressed = sink.0.borrow().clone();
+        compressed
+    }
+
+    #[test]
+    fn multithreaded_zstd_round_trip() {
+        // More than one job of the background threads at this level
+        let data = log_lines(24 << 20);
+
+        for threads in [1, 4] {
+            let compressed = compress(&data, 3, threads);
+
+            let mut decoded = Vec::new();
+            Codec::Zstd
+                .decoder(io::Cursor::new(compressed))
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert!(decoded == data, "{} threads", threads);
+        }
+    }
+
+    /// Compares the throughput of one compression thread with one per core, run with
+    /// `cargo test --release -- --ignored --nocapture zstd_throughput`.
+    #[test]
+    #[ignore]
+    fn zstd_throughput() {
+        let data = log_lines(256 << 20);
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+
+        let mut rates = Vec::new();
+        for threads in [1, cores] {
+            let started = Instant::now();
+            let compressed = compress(&data, 9, threads);
+            let rate = data.len() as f64 / started.elapsed().as_secs_f64() / 1e6;
+            println!(
+                "{} threads: {:.1} MB/s, compressed to {:.1}%",
+                threads,
+                rate,
+                compressed.len() as f64 / data.len() as f64 * 100.
+            );
+            rates.push(rate);
+        }
+
+        if cores > 1 {
+            assert!(rates[1] > rates[0] * 1.5);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// The start of a frame of a log file, which decodes on its own.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// Position of the frame in the log file, in bytes
+    pub offset: u64,
+    /// Seconds since the Unix epoch of the first message of the frame
+    pub time: f64,
+    /// Messages before the frame, counted from where the logger started writing to the file
+    pub messages: u64,
+}
+
+/// The index of a log file, next to it with `.idx` added, e.g. `log.json.zst.idx`.
+pub fn index_path(log: &Path) -> PathBuf {
+    let mut path = log.as_os_str().to_owned();
+    path.push(".idx");
+    path.into()
+}
+
+/// Adds entries to the index of a log file, one JSON object per line.
+pub struct IndexWriter {
+    file: File,
+}
+
+impl IndexWriter {
+    /// Opens the index of the log file at `log`. The index of a log file which is appended to
+    /// is continued, otherwise it is started over.
+    pub fn open(log: &Path, append: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .append(append)
+            .write(true)
+            .truncate(!append)
+            .create(true)
+            .open(index_path(log))?;
+
+        Ok(IndexWriter { file })
+    }
+
+    /// Adds an entry with a single write, so a crash can only cut off the last line.
+    pub fn add(&mut self, entry: &IndexEntry) -> anyhow::Result<()> {
+        let line = serde_json::to_string(entry)? + "\n";
+        self.file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Reads the index of the log file at `log`, `None` if it has no index. Reading stops at the
+/// first entry which can not be read, e.g. a half written one, or which is past the end of the
+/// log file, e.g. after a crash lost the end of the log file but not of the index.
+pub fn read_index(log: &Path) -> io::Result<Option<Vec<IndexEntry>>> {
+    let log_len = log.metadata()?.len();
+
+    let file = match File::open(index_path(log)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str::<IndexEntry>(&line?) {
+            Ok(entry) if entry.offset < log_len => entries.push(entry),
+            _ => break,
+        }
+    }
+
+    Ok(Some(entries))
+}
+
+/// The offset of the last frame starting at or before `time`, where reading finds all messages
+/// from `time` on.
+pub fn seek_offset(entries: &[IndexEntry], time: f64) -> u64 {
+    seek_frame(entries, time).map_or(0, |entry| entry.offset)
+}
+
+/// The last frame starting at or before `time`, none if reading needs to start at the beginning.
+pub fn seek_frame(entries: &[IndexEntry], time: f64) -> Option<&IndexEntry> {
+    let frame = entries.partition_point(|entry| entry.time <= time);
+
+    match frame {
+        0 => None,
+        frame => Some(&entries[frame - 1]),
+    }
+}
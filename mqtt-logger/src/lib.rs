@@ -0,0 +1,2644 @@
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, Utc};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::RegexSet;
+use rumqttc::{
+    AsyncClient, Client, ClientConfig, ClientError, ConnectionError, Event, Incoming, Key,
+    LastWill, MqttOptions, Outgoing, Publish, QoS, TlsConfiguration, Transport,
+};
+use rustls::TLSError;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+pub mod codec;
+pub mod index;
+pub mod metrics;
+pub mod reader;
+pub mod writer;
+
+use codec::Codec;
+use index::{IndexEntry, IndexWriter};
+use metrics::{Metrics, MetricsServer};
+use writer::{CountingWriter, Format, MessageWriter, SerializeError, SplitWriter, SyncOnFlush};
+
+// Reference:
+// {"time": 1611137748.0325797, "qos": 0, "retain": true, "topic": "kvarntorp-test/gateway/165640a7e023861a/nodeversion", "msg_b64": "IjAuMi4xNSI="}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MqttMessage {
+    #[serde(flatten)]
+    pub time: Time,
+    /// Position in arrival order within the logging session, only written with --sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub qos: u8,
+    pub retain: bool,
+    /// Whether the publish was a redelivery, only written with --protocol-fields
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dup: Option<bool>,
+    /// The packet identifier, 0 for QoS 0, only written with --protocol-fields
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pkid: Option<u16>,
+    pub topic: String,
+    /// Set when the payload was truncated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+    /// The published payload size, only written when the payload was truncated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orig_len: Option<usize>,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+/// Identifies the record layout of JSON logs, changed when old readers can not read new logs.
+pub const LOG_FORMAT: &str = "mqtt-logger/1";
+
+/// Marks a record as a header, data records have no `type`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordType {
+    Header,
+}
+
+/// The first record of a new JSON log file, describing the capture.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogHeader {
+    #[serde(rename = "type")]
+    pub record_type: RecordType,
+    /// [`LOG_FORMAT`] of the logger which wrote the file
+    pub format: String,
+    /// Version of the logger which wrote the file
+    pub version: String,
+    /// Seconds since the Unix epoch when the capture started
+    pub started: f64,
+    pub server: String,
+    pub topics: Vec<String>,
+}
+
+impl LogHeader {
+    pub fn new(started: f64, server: impl Into<String>, topics: Vec<String>) -> Self {
+        LogHeader {
+            record_type: RecordType::Header,
+            format: LOG_FORMAT.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            started,
+            server: server.into(),
+            topics,
+        }
+    }
+}
+
+impl MqttMessage {
+    /// Converts a received publish, UTF-8 payloads are kept as text if `text_payloads` is set.
+    pub fn from_publish(
+        publish: Publish,
+        time: Time,
+        text_payloads: bool,
+        encoding: PayloadEncoding,
+    ) -> Self {
+        let payload = match std::str::from_utf8(&publish.payload) {
+            Ok(text) if text_payloads => Payload::Text(text.to_string()),
+            _ => encoding.encode(&publish.payload),
+        };
+
+        MqttMessage {
+            time,
+            seq: None,
+            qos: publish.qos as u8,
+            retain: publish.retain,
+            dup: None,
+            pkid: None,
+            topic: publish.topic,
+            truncated: None,
+            orig_len: None,
+            payload,
+        }
+    }
+}
+
+/// The receive time, serialized with the fields of its format.
+///
+/// When deserializing, the first variant whose fields are all present is used, so the one with
+/// the most fields comes first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Time {
+    /// Seconds since the Unix epoch, and an RFC 3339 timestamp in UTC for humans
+    Rfc3339 { time: f64, time_iso: String },
+    /// Seconds since the Unix epoch
+    Unix { time: f64 },
+    /// Milliseconds since the Unix epoch
+    UnixMillis { time_ms: u64 },
+}
+
+impl Time {
+    /// Seconds since the Unix epoch.
+    pub fn secs(&self) -> f64 {
+        match self {
+            Time::Rfc3339 { time, .. } | Time::Unix { time } => *time,
+            Time::UnixMillis { time_ms } => *time_ms as f64 / 1000.,
+        }
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Time::Unix { time } => write!(f, "{}", time),
+            Time::UnixMillis { time_ms } => write!(f, "{}", time_ms),
+            Time::Rfc3339 { time_iso, .. } => write!(f, "{}", time_iso),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TimeFormat {
+    Unix,
+    UnixMillis,
+    Rfc3339,
+}
+
+impl FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unix" | "epoch" => Ok(TimeFormat::Unix),
+            "unix-millis" => Ok(TimeFormat::UnixMillis),
+            "rfc3339" | "iso8601" => Ok(TimeFormat::Rfc3339),
+            _ => Err(anyhow!(
+                "Unknown time format '{}', expected unix, unix-millis or rfc3339",
+                s
+            )),
+        }
+    }
+}
+
+/// The time zone of RFC 3339 timestamps.
+#[derive(Debug, Clone, Copy)]
+pub enum TimeZone {
+    Utc,
+    Local,
+    Offset(FixedOffset),
+}
+
+impl FromStr for TimeZone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" | "UTC" | "Z" => Ok(TimeZone::Utc),
+            "local" => Ok(TimeZone::Local),
+            _ => s.parse().map(TimeZone::Offset).map_err(|_| {
+                anyhow!(
+                    "Unknown time zone '{}', expected utc, local or an offset like +02:00",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl TimeZone {
+    fn format(&self, time: SystemTime) -> String {
+        let utc = DateTime::<Utc>::from(time);
+
+        match self {
+            TimeZone::Utc => utc.to_rfc3339_opts(SecondsFormat::Micros, true),
+            TimeZone::Local => {
+                DateTime::<Local>::from(time).to_rfc3339_opts(SecondsFormat::Micros, true)
+            }
+            TimeZone::Offset(offset) => utc
+                .with_timezone(offset)
+                .to_rfc3339_opts(SecondsFormat::Micros, true),
+        }
+    }
+}
+
+impl TimeFormat {
+    pub fn time(&self, time: SystemTime, zone: TimeZone) -> Time {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        match self {
+            TimeFormat::Unix => Time::Unix {
+                time: since_epoch.as_secs_f64(),
+            },
+            TimeFormat::UnixMillis => Time::UnixMillis {
+                time_ms: since_epoch.as_millis() as u64,
+            },
+            TimeFormat::Rfc3339 => Time::Rfc3339 {
+                time: since_epoch.as_secs_f64(),
+                time_iso: zone.format(time),
+            },
+        }
+    }
+}
+
+/// The encoded payload, serialized with a field name telling the encoding.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Payload {
+    /// Text payloads, only used with --text-payloads
+    #[serde(rename = "msg", alias = "msg_str")]
+    Text(String),
+    #[serde(rename = "msg_b64")]
+    Base64(String),
+    #[serde(rename = "msg_hex")]
+    Hex(String),
+    /// Raw payloads, only used by binary formats
+    #[serde(rename = "msg_bytes", with = "serde_bytes")]
+    Bytes(Vec<u8>),
+}
+
+impl Payload {
+    /// The payload as it was published.
+    pub fn decode(self) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Payload::Text(text) => text.into_bytes(),
+            Payload::Base64(b64) => base64::decode(b64)?,
+            Payload::Hex(hex) => hex::decode(hex)?,
+            Payload::Bytes(bytes) => bytes,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PayloadEncoding {
+    Base64,
+    Hex,
+    /// Kept as bytes, used for the MessagePack format
+    Raw,
+}
+
+impl FromStr for PayloadEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(PayloadEncoding::Base64),
+            "hex" => Ok(PayloadEncoding::Hex),
+            _ => Err(anyhow!(
+                "Unknown payload encoding '{}', expected base64 or hex",
+                s
+            )),
+        }
+    }
+}
+
+impl PayloadEncoding {
+    pub fn encode(&self, payload: &[u8]) -> Payload {
+        match self {
+            PayloadEncoding::Base64 => Payload::Base64(base64::encode(payload)),
+            PayloadEncoding::Hex => Payload::Hex(hex::encode(payload)),
+            PayloadEncoding::Raw => Payload::Bytes(payload.to_vec()),
+        }
+    }
+}
+
+/// What to do with payloads above the maximum size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversize {
+    /// Drop the message
+    Skip,
+    /// Log the start of the payload, marked as `truncated` with the original size in `orig_len`
+    Truncate,
+}
+
+impl FromStr for Oversize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Oversize::Skip),
+            "truncate" => Ok(Oversize::Truncate),
+            _ => Err(anyhow!(
+                "Unknown oversize action '{}', expected skip or truncate",
+                s
+            )),
+        }
+    }
+}
+
+/// Which messages share a sampling counter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleScope {
+    /// Each topic is sampled on its own, so rare topics are still logged
+    Topic,
+    /// One counter for all messages
+    Global,
+}
+
+impl FromStr for SampleScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "topic" => Ok(SampleScope::Topic),
+            "global" => Ok(SampleScope::Global),
+            _ => Err(anyhow!(
+                "Unknown sample scope '{}', expected topic or global",
+                s
+            )),
+        }
+    }
+}
+
+/// Which live messages to log, retained messages are always logged.
+#[derive(Debug, Clone, Copy)]
+pub enum Sampling {
+    /// Every nth message of the scope, starting with the first
+    Every(u64, SampleScope),
+    /// Each message with this probability, the seed makes the selection reproducible
+    Rate { rate: f64, seed: Option<u64> },
+}
+
+/// The selection state of a [`Sampling`].
+enum Sampler {
+    Every(u64, SampleScope, HashMap<String, u64>),
+    Rate(f64, Box<StdRng>),
+}
+
+impl Sampler {
+    fn new(sampling: Sampling) -> Self {
+        match sampling {
+            Sampling::Every(n, scope) => Sampler::Every(n, scope, HashMap::new()),
+            Sampling::Rate { rate, seed } => Sampler::Rate(
+                rate,
+                Box::new(match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                }),
+            ),
+        }
+    }
+
+    fn keep(&mut self, topic: &str) -> bool {
+        match self {
+            Sampler::Every(n, scope, counters) => {
+                let key = match scope {
+                    SampleScope::Topic => topic,
+                    SampleScope::Global => "",
+                };
+                let counter = counters.entry(key.to_string()).or_insert(0);
+                let keep = *counter % *n == 0;
+                *counter += 1;
+                keep
+            }
+            Sampler::Rate(rate, rng) => rng.gen_bool(*rate),
+        }
+    }
+}
+
+/// Progress output while logging to files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress {
+    Off,
+    /// A spinner for terminals
+    Spinner,
+    /// A plain line on stderr with this interval, for redirected output and journald
+    Lines(Duration),
+}
+
+/// Where logged messages go.
+pub enum Output {
+    /// Print messages to stdout instead of logging them
+    Print,
+    /// Write uncompressed records into a shared writer, e.g. an in-memory buffer
+    Writer(Arc<Mutex<dyn Write + Send>>, Format),
+    /// Compressed log files
+    Files(LogFiles),
+}
+
+/// Settings of compressed log files.
+#[derive(Debug, Clone)]
+pub struct LogFiles {
+    /// The log file, or the directory of log files when splitting by topic
+    pub path: PathBuf,
+    pub format: Format,
+    pub codec: Codec,
+    pub compression_level: i32,
+    /// Compression threads, only used by zstd
+    pub compression_threads: u32,
+    /// Append to an existing file instead of failing
+    pub append: bool,
+    /// Start a new timestamped file with this interval
+    pub rotate_interval: Option<Duration>,
+    /// Rotate on multiples of the interval since the epoch, instead of relative to the start
+    pub align_rotation: bool,
+    /// Start a new numbered file when the compressed file reaches this size
+    pub max_size: Option<u64>,
+    /// Write one file per prefix of this many topic levels
+    pub split_by_topic: Option<usize>,
+    /// Start a new compressed frame with this interval, listed in an index next to the file
+    pub frame_interval: Option<Duration>,
+    pub max_open_files: usize,
+    /// Sync the file to disk whenever the log is flushed
+    pub fsync: bool,
+    /// Capacity of the write buffer below the compression, per open file
+    pub buffer_size: usize,
+    /// Start new JSON log files with a [`LogHeader`]
+    pub header: bool,
+}
+
+impl LogFiles {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        LogFiles {
+            path: path.into(),
+            format: Format::Json,
+            codec: Codec::Zstd,
+            compression_level: 9,
+            compression_threads: 1,
+            append: false,
+            rotate_interval: None,
+            align_rotation: false,
+            max_size: None,
+            split_by_topic: None,
+            frame_interval: None,
+            max_open_files: 64,
+            fsync: false,
+            buffer_size: 128 * 1024,
+            header: true,
+        }
+    }
+
+    /// The extension of the log files, e.g. `json.zst`.
+    pub fn extension(&self) -> String {
+        match self.codec.extension() {
+            Some(codec) => format!("{}.{}", self.format.extension(), codec),
+            None => self.format.extension().to_string(),
+        }
+    }
+
+    /// The path of a new log file, timestamped when rotating by time.
+    fn new_path(&self) -> PathBuf {
+        let mut path = if self.rotate_interval.is_some() {
+            timestamped_path(&self.path, &self.extension(), Utc::now())
+        } else {
+            self.path.clone()
+        };
+
+        // When splitting by topic the path is a directory of log files
+        if self.split_by_topic.is_none() {
+            set_log_extension(&mut path, &self.extension());
+        }
+
+        path
+    }
+
+    /// Opens the writer of the log file, or files, at `path`.
+    fn open(
+        &self,
+        path: &Path,
+        file_size: &Arc<AtomicU64>,
+        header: &LogHeader,
+    ) -> anyhow::Result<Box<dyn MessageWriter>> {
+        let level = match self.split_by_topic {
+            Some(level) => level,
+            None => return open_log_file(path, self, false, file_size, header),
+        };
+
+        let dir = path.to_path_buf();
+        let files = self.clone();
+        let header = header.clone();
+
+        Ok(Box::new(SplitWriter::new(
+            level,
+            self.max_open_files,
+            Box::new(move |prefix, reopen| {
+                let path = dir.join(format!("{}.{}", prefix.display(), files.extension()));
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                // Sizes are only tracked for --max-size, which is not used when splitting
+                open_log_file(&path, &files, reopen, &Arc::new(AtomicU64::new(0)), &header)
+            }),
+        )))
+    }
+}
+
+/// The current frame of a log file with an index.
+struct Frames {
+    /// The log file
+    path: PathBuf,
+    index: IndexWriter,
+    /// Position of the current frame in the log file
+    offset: u64,
+    /// When the current frame started, none until its first message
+    started: Option<Instant>,
+    /// Messages written to the log file
+    messages: u64,
+}
+
+impl Frames {
+    /// Indexes the just opened log file at `path`, an empty file gets a new index.
+    fn open(path: &Path, file_size: &AtomicU64) -> anyhow::Result<Self> {
+        let offset = file_size.load(Ordering::SeqCst);
+
+        Ok(Frames {
+            path: path.to_path_buf(),
+            index: IndexWriter::open(path, offset > 0)?,
+            offset,
+            started: None,
+            messages: 0,
+        })
+    }
+}
+
+/// Subscribes to an MQTT broker and logs the messages, created with [`LoggerBuilder`].
+pub struct Logger {
+    server: String,
+    port: u16,
+    /// All brokers, starting with `server` and `port`
+    servers: Vec<(String, u16)>,
+    client_id: String,
+    topics: Vec<String>,
+    qos: QoS,
+    exclude: Vec<String>,
+    include_regex: Option<RegexSet>,
+    exclude_regex: Option<RegexSet>,
+    credentials: Option<(String, String)>,
+    last_will: Option<LastWill>,
+    tls: bool,
+    custom_ca: Option<Vec<u8>>,
+    client_auth: Option<(Vec<u8>, Key)>,
+    websocket: Option<String>,
+    keep_alive: Duration,
+    clean_session: bool,
+    output: Output,
+    output_path: Option<PathBuf>,
+    text_payloads: bool,
+    payload_encoding: PayloadEncoding,
+    stop_after: Option<Duration>,
+    max_messages: Option<u64>,
+    snapshot: Option<Duration>,
+    skip_retained: bool,
+    skip_empty: bool,
+    sequence: bool,
+    color: bool,
+    protocol_fields: bool,
+    dedup: bool,
+    max_payload: Option<(usize, Oversize)>,
+    sampling: Option<Sampling>,
+    metrics_addr: Option<SocketAddr>,
+    flush_interval: Option<Duration>,
+    max_reconnects: Option<u32>,
+    progress: Progress,
+    async_io: bool,
+    channel_capacity: usize,
+    reconnect_base: Duration,
+    reconnect_max: Duration,
+    time_format: TimeFormat,
+    time_zone: TimeZone,
+}
+
+/// Builder of a [`Logger`], with the same defaults as the command line.
+pub struct LoggerBuilder {
+    server: String,
+    port: Option<u16>,
+    fallback_servers: Vec<(String, Option<u16>)>,
+    client_id: Option<String>,
+    topics: Vec<String>,
+    qos: QoS,
+    exclude: Vec<String>,
+    include_regex: Vec<String>,
+    exclude_regex: Vec<String>,
+    credentials: Option<(String, String)>,
+    last_will: Option<LastWill>,
+    tls: bool,
+    custom_ca: Option<Vec<u8>>,
+    client_auth: Option<(Vec<u8>, Key)>,
+    websocket: Option<String>,
+    keep_alive: Duration,
+    clean_session: bool,
+    output: Output,
+    text_payloads: bool,
+    payload_encoding: PayloadEncoding,
+    stop_after: Option<Duration>,
+    max_messages: Option<u64>,
+    snapshot: Option<Duration>,
+    skip_retained: bool,
+    skip_empty: bool,
+    sequence: bool,
+    color: bool,
+    protocol_fields: bool,
+    dedup: bool,
+    max_payload: Option<(usize, Oversize)>,
+    sampling: Option<Sampling>,
+    metrics_addr: Option<SocketAddr>,
+    flush_interval: Option<Duration>,
+    max_reconnects: Option<u32>,
+    progress: Progress,
+    async_io: bool,
+    channel_capacity: usize,
+    reconnect_base: Duration,
+    reconnect_max: Duration,
+    time_format: TimeFormat,
+    time_zone: TimeZone,
+}
+
+impl Default for LoggerBuilder {
+    fn default() -> Self {
+        LoggerBuilder {
+            server: "localhost".to_string(),
+            port: None,
+            fallback_servers: Vec::new(),
+            client_id: None,
+            topics: vec!["#".to_string()],
+            qos: QoS::AtLeastOnce,
+            exclude: Vec::new(),
+            include_regex: Vec::new(),
+            exclude_regex: Vec::new(),
+            credentials: None,
+            last_will: None,
+            tls: false,
+            custom_ca: None,
+            client_auth: None,
+            websocket: None,
+            keep_alive: Duration::from_secs(5),
+            clean_session: true,
+            output: Output::Print,
+            text_payloads: false,
+            payload_encoding: PayloadEncoding::Base64,
+            stop_after: None,
+            max_messages: None,
+            snapshot: None,
+            skip_retained: false,
+            skip_empty: false,
+            sequence: false,
+            color: false,
+            protocol_fields: false,
+            dedup: false,
+            max_payload: None,
+            sampling: None,
+            metrics_addr: None,
+            flush_interval: None,
+            max_reconnects: None,
+            progress: Progress::Off,
+            async_io: false,
+            channel_capacity: 128,
+            reconnect_base: Duration::from_millis(500),
+            reconnect_max: Duration::from_secs(30),
+            time_format: TimeFormat::Unix,
+            time_zone: TimeZone::Utc,
+        }
+    }
+}
+
+impl LoggerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The hostname or IP address of the broker, IPv6 addresses may be bracketed, e.g. `[::1]`.
+    pub fn server(mut self, server: impl Into<String>) -> Self {
+        self.server = server.into();
+        self
+    }
+
+    /// The port, if not set 8883 with TLS and 1883 otherwise, or 443 and 80 over WebSockets.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// A broker to try when the connection fails, after `server` and the fallbacks added before.
+    /// The port defaults like the one of `server`.
+    pub fn fallback_server(mut self, server: impl Into<String>, port: Option<u16>) -> Self {
+        self.fallback_servers.push((server.into(), port));
+        self
+    }
+
+    /// The MQTT client id, a unique one is generated if not set.
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Topic filters to subscribe to, also used when resubscribing after a reconnect.
+    pub fn topics(mut self, topics: Vec<String>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Topic filters of messages to drop.
+    pub fn exclude(mut self, exclude: Vec<String>) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Only log messages with a topic matching one of these regular expressions.
+    pub fn include_regex(mut self, patterns: Vec<String>) -> Self {
+        self.include_regex = patterns;
+        self
+    }
+
+    /// Drop messages with a topic matching one of these regular expressions, also when they
+    /// match an include regex.
+    pub fn exclude_regex(mut self, patterns: Vec<String>) -> Self {
+        self.exclude_regex = patterns;
+        self
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// With a persistent session, i.e. `false`, the broker keeps the subscriptions and queues QoS 1
+    /// and 2 messages while the logger is disconnected, and delivers them on reconnect. This
+    /// needs a fixed client id.
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// A message the broker publishes if the logger disconnects without closing the connection.
+    /// Both the topic and the payload need to be given.
+    pub fn last_will(
+        mut self,
+        topic: impl Into<String>,
+        payload: impl Into<Vec<u8>>,
+        qos: QoS,
+        retain: bool,
+    ) -> Self {
+        self.last_will = Some(LastWill::new(topic, payload, qos, retain));
+        self
+    }
+
+    /// Use TLS with the system root certificates, unless a custom CA is set.
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// A custom CA (PEM), implies TLS.
+    pub fn custom_ca(mut self, ca: Vec<u8>) -> Self {
+        self.custom_ca = Some(ca);
+        self
+    }
+
+    /// A client certificate and key for mutual TLS, see [`load_client_auth`]. Implies TLS.
+    pub fn client_auth(mut self, cert: Vec<u8>, key: Key) -> Self {
+        self.client_auth = Some((cert, key));
+        self
+    }
+
+    /// Connect over a WebSocket with this URL path, e.g. `/mqtt`. Uses a secure WebSocket with TLS.
+    pub fn websocket(mut self, path: impl Into<String>) -> Self {
+        self.websocket = Some(path.into());
+        self
+    }
+
+    /// The keep-alive interval, at least 5 seconds.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Store UTF-8 payloads as text, other payloads use the payload encoding.
+    pub fn text_payloads(mut self, text_payloads: bool) -> Self {
+        self.text_payloads = text_payloads;
+        self
+    }
+
+    pub fn time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+
+    /// The time zone of RFC 3339 timestamps, UTC by default.
+    pub fn time_zone(mut self, time_zone: TimeZone) -> Self {
+        self.time_zone = time_zone;
+        self
+    }
+
+    pub fn payload_encoding(mut self, payload_encoding: PayloadEncoding) -> Self {
+        self.payload_encoding = payload_encoding;
+        self
+    }
+
+    /// Stop logging after this duration.
+    pub fn stop_after(mut self, duration: Duration) -> Self {
+        self.stop_after = Some(duration);
+        self
+    }
+
+    /// Stop logging after this many messages.
+    pub fn max_messages(mut self, max_messages: u64) -> Self {
+        self.max_messages = Some(max_messages);
+        self
+    }
+
+    /// Only log retained messages, and stop when none has arrived within the quiet period.
+    pub fn snapshot(mut self, quiet_period: Duration) -> Self {
+        self.snapshot = Some(quiet_period);
+        self
+    }
+
+    /// Drop retained messages, e.g. the ones the broker sends when subscribing.
+    pub fn skip_retained(mut self, skip_retained: bool) -> Self {
+        self.skip_retained = skip_retained;
+        self
+    }
+
+    /// Drop messages with an empty payload. These also clear retained messages, which is lost.
+    pub fn skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// Color the topics of printed messages, for watching them in a terminal.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Number the messages in arrival order, to order records with identical timestamps.
+    pub fn sequence(mut self, sequence: bool) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Record the DUP flag and packet identifier of each publish, to debug redeliveries.
+    pub fn protocol_fields(mut self, protocol_fields: bool) -> Self {
+        self.protocol_fields = protocol_fields;
+        self
+    }
+
+    /// Drop messages with the same payload and retain flag as the last message on their topic.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Skip or truncate messages with a payload larger than `max_bytes`.
+    pub fn max_payload(mut self, max_bytes: usize, oversize: Oversize) -> Self {
+        self.max_payload = Some((max_bytes, oversize));
+        self
+    }
+
+    /// Only log a sample of the live messages.
+    pub fn sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP on this address while logging.
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Flush the log with this interval, so a crash loses at most this much of the log.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval);
+        self
+    }
+
+    /// Fail after this many consecutive failed attempts to reconnect to the broker.
+    pub fn max_reconnects(mut self, max_reconnects: u32) -> Self {
+        self.max_reconnects = Some(max_reconnects);
+        self
+    }
+
+    /// How to show the progress while logging to files.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Drive the MQTT event loop with tokio and buffer received messages without bound, so
+    /// bursts are received while the log file is being written.
+    pub fn async_io(mut self, async_io: bool) -> Self {
+        self.async_io = async_io;
+        self
+    }
+
+    /// The number of received notifications buffered while the log file is written, not used
+    /// with async IO which buffers without bound. Also the capacity of the request channel of
+    /// the MQTT client. Larger capacities use more memory.
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    /// The delay before reconnecting, doubled for every failed attempt up to `max`.
+    pub fn reconnect_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.reconnect_base = base;
+        self.reconnect_max = max;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Logger> {
+        if self.topics.is_empty() {
+            return Err(anyhow!("No topics supplied"));
+        }
+
+        if let Some(will) = &self.last_will {
+            if will.topic.is_empty() || will.message.is_empty() {
+                return Err(anyhow!("The last will needs a topic and a payload"));
+            }
+            if will.topic.contains(['+', '#']) {
+                return Err(anyhow!(
+                    "The last will topic '{}' can not contain wildcards",
+                    will.topic
+                ));
+            }
+        }
+
+        if self.skip_retained && self.snapshot.is_some() {
+            return Err(anyhow!(
+                "A snapshot only records retained messages, they can not be skipped"
+            ));
+        }
+
+        if self.keep_alive < Duration::from_secs(5) {
+            return Err(anyhow!(
+                "The keep-alive needs to be at least 5 seconds, it can not be disabled by the MQTT client"
+            ));
+        }
+
+        match self.sampling {
+            Some(Sampling::Every(0, _)) => {
+                return Err(anyhow!("Sampling needs to keep every 1st message or fewer"))
+            }
+            Some(Sampling::Rate { rate, .. }) if !(0.0..=1.0).contains(&rate) => {
+                return Err(anyhow!("The sample rate needs to be between 0 and 1"))
+            }
+            _ => {}
+        }
+
+        if self.flush_interval == Some(Duration::ZERO) {
+            return Err(anyhow!("The flush interval needs to be more than 0"));
+        }
+
+        let include_regex = regex_set(&self.include_regex).context("Invalid include regex")?;
+        let exclude_regex = regex_set(&self.exclude_regex).context("Invalid exclude regex")?;
+
+        let output_path = match &self.output {
+            Output::Files(files) => {
+                if let Some(interval) = files.rotate_interval {
+                    if interval < Duration::from_secs(1) {
+                        return Err(anyhow!(
+                            "The rotation interval needs to be more than 1 second"
+                        ));
+                    }
+                }
+
+                if let Some(levels) = files.codec.level_range() {
+                    if !levels.contains(&files.compression_level) {
+                        return Err(anyhow!(
+                            "Compression level {} is out of range for {:?}, expected {} to {}",
+                            files.compression_level,
+                            files.codec,
+                            levels.start(),
+                            levels.end()
+                        ));
+                    }
+                }
+
+                if files.split_by_topic == Some(0) {
+                    return Err(anyhow!("Splitting by topic needs at least 1 topic level"));
+                }
+
+                if files.split_by_topic.is_some() && files.max_open_files == 0 {
+                    return Err(anyhow!("Splitting by topic needs at least 1 open file"));
+                }
+
+                if files.frame_interval.is_some() {
+                    if files.split_by_topic.is_some() {
+                        return Err(anyhow!("Frames can not be indexed when splitting by topic"));
+                    }
+                    // MessagePack logs can only be read from the start, after the magic
+                    if files.format != Format::Json {
+                        return Err(anyhow!("Frames are only indexed for JSON logs"));
+                    }
+                }
+
+                Some(files.new_path())
+            }
+            _ => None,
+        };
+
+        // MessagePack stores payloads as bytes, no need to encode them
+        let payload_encoding = match &self.output {
+            Output::Writer(_, Format::MsgPack) => PayloadEncoding::Raw,
+            Output::Files(files) if files.format == Format::MsgPack => PayloadEncoding::Raw,
+            _ => self.payload_encoding,
+        };
+
+        if self.channel_capacity == 0 {
+            return Err(anyhow!("The channel capacity needs to be at least 1"));
+        }
+
+        if !self.clean_session && self.client_id.is_none() {
+            return Err(anyhow!(
+                "A persistent session needs a client id, the broker finds the session by it"
+            ));
+        }
+
+        let tls = self.tls || self.custom_ca.is_some() || self.client_auth.is_some();
+        let client_id = self.client_id.unwrap_or_else(|| {
+            let nanos = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or(Duration::new(0, 1))
+                .subsec_nanos();
+            format!("mqtt-logger-sub{}", nanos)
+        });
+
+        let default_port = match (self.websocket.is_some(), tls) {
+            (false, false) => 1883,
+            (false, true) => 8883,
+            (true, false) => 80,
+            (true, true) => 443,
+        };
+        let port = self.port.unwrap_or(default_port);
+        let server = host(&self.server)?;
+        let mut servers = vec![(server.clone(), port)];
+        for (server, port) in self.fallback_servers {
+            servers.push((host(&server)?, port.unwrap_or(default_port)));
+        }
+
+        Ok(Logger {
+            server,
+            port,
+            servers,
+            client_id,
+            topics: self.topics,
+            qos: self.qos,
+            exclude: self.exclude,
+            include_regex,
+            exclude_regex,
+            credentials: self.credentials,
+            last_will: self.last_will,
+            tls,
+            custom_ca: self.custom_ca,
+            client_auth: self.client_auth,
+            websocket: self.websocket.map(|path| {
+                if path.starts_with('/') {
+                    path
+                } else {
+                    format!("/{}", path)
+                }
+            }),
+            keep_alive: self.keep_alive,
+            clean_session: self.clean_session,
+            output: self.output,
+            output_path,
+            text_payloads: self.text_payloads,
+            payload_encoding,
+            stop_after: self.stop_after,
+            max_messages: self.max_messages,
+            snapshot: self.snapshot,
+            skip_retained: self.skip_retained,
+            skip_empty: self.skip_empty,
+            sequence: self.sequence,
+            color: self.color,
+            protocol_fields: self.protocol_fields,
+            dedup: self.dedup,
+            max_payload: self.max_payload,
+            sampling: self.sampling,
+            metrics_addr: self.metrics_addr,
+            flush_interval: self.flush_interval,
+            max_reconnects: self.max_reconnects,
+            progress: self.progress,
+            async_io: self.async_io,
+            channel_capacity: self.channel_capacity,
+            reconnect_base: self.reconnect_base,
+            reconnect_max: self.reconnect_max,
+            time_format: self.time_format,
+            time_zone: self.time_zone,
+        })
+    }
+}
+
+/// The host of a server given as a hostname, an IPv4 address or an IPv6 address, which may be
+/// bracketed like in a URL, e.g. `[2001:db8::1]`. The port is given on its own.
+fn host(server: &str) -> anyhow::Result<String> {
+    let host = match server.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => host,
+            Some(_) => {
+                return Err(anyhow!(
+                    "Unexpected text after the address in '{}', the port is given on its own",
+                    server
+                ))
+            }
+            None => return Err(anyhow!("Missing ']' after the address in '{}'", server)),
+        },
+        None => server,
+    };
+
+    if host.is_empty() {
+        return Err(anyhow!("No server supplied"));
+    }
+    if host.contains(':') && host.parse::<std::net::Ipv6Addr>().is_err() {
+        return Err(anyhow!(
+            "'{}' is neither a hostname nor an IPv6 address, the port is given on its own",
+            host
+        ));
+    }
+
+    Ok(host.to_string())
+}
+
+impl Logger {
+    pub fn builder() -> LoggerBuilder {
+        LoggerBuilder::new()
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn topics(&self) -> &[String] {
+        &self.topics
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// The address of the broker, e.g. `mqtts://localhost:8883` or `ws://localhost:80/mqtt`.
+    pub fn address(&self) -> String {
+        self.address_of(&self.server, self.port)
+    }
+
+    /// The addresses of the brokers tried in turn when the connection to the broker fails.
+    pub fn fallback_addresses(&self) -> Vec<String> {
+        self.servers[1..]
+            .iter()
+            .map(|(server, port)| self.address_of(server, *port))
+            .collect()
+    }
+
+    fn address_of(&self, server: &str, port: u16) -> String {
+        let scheme = match (&self.websocket, self.tls) {
+            (None, false) => "mqtt",
+            (None, true) => "mqtts",
+            (Some(_), false) => "ws",
+            (Some(_), true) => "wss",
+        };
+
+        // IPv6 addresses are bracketed to tell them apart from the port
+        let server = if server.contains(':') {
+            format!("[{}]", server)
+        } else {
+            server.to_string()
+        };
+
+        format!(
+            "{}://{}:{}{}",
+            scheme,
+            server,
+            port,
+            self.websocket.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// The connection options of the broker at `server` and `port`.
+    fn mqtt_options(&self, server: &str, port: u16) -> anyhow::Result<MqttOptions> {
+        // WebSocket transports take the whole URL as the broker address
+        let mut mqtt_options = match &self.websocket {
+            Some(_) => MqttOptions::new(&self.client_id, self.address_of(server, port), port),
+            None => MqttOptions::new(&self.client_id, server, port),
+        };
+
+        // Encrypted MQTT?
+        if self.tls {
+            let tls_config = if let Some(custom_ca) = &self.custom_ca {
+                TlsConfiguration::Simple {
+                    ca: custom_ca.clone(),
+                    alpn: None,
+                    client_auth: self.client_auth.clone(),
+                }
+            } else {
+                let mut client_config = ClientConfig::new();
+                // Use rustls-native-certs to load root certificates from the operating system.
+                client_config.root_store = rustls_native_certs::load_native_certs()
+                    .map_err(|(_, e)| e)
+                    .context("Failed to load the platform root certificates")?;
+
+                if let Some((cert, key)) = &self.client_auth {
+                    set_client_auth(&mut client_config, cert, key)?;
+                }
+
+                client_config.into()
+            };
+
+            mqtt_options.set_transport(match self.websocket {
+                Some(_) => Transport::wss_with_config(tls_config),
+                None => Transport::tls_with_config(tls_config),
+            });
+        } else if self.websocket.is_some() {
+            mqtt_options.set_transport(Transport::ws());
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            mqtt_options.set_credentials(username, password);
+        }
+
+        if let Some(will) = &self.last_will {
+            mqtt_options.set_last_will(will.clone());
+        }
+
+        mqtt_options.set_keep_alive(self.keep_alive);
+        mqtt_options.set_clean_session(self.clean_session);
+
+        Ok(mqtt_options)
+    }
+
+    /// Shows the final progress message, which also ends the spinner.
+    fn finish_progress(&self, pb: &ProgressBar, message: String) {
+        match self.progress {
+            Progress::Lines(_) => eprintln!("{}", message),
+            _ => pb.finish_with_message(message),
+        }
+    }
+
+    /// The first log file, or directory of log files, when logging to files.
+    pub fn output_path(&self) -> Option<&Path> {
+        self.output_path.as_deref()
+    }
+
+    /// Logs until the shutdown flag is set or a configured limit is reached.
+    pub fn run(&self, shutdown: Arc<AtomicBool>) -> anyhow::Result<()> {
+        // Compressed size of the current log file, used for --max-size
+        let file_size = Arc::new(AtomicU64::new(0));
+        let mut file_number = 0;
+        let mut current_output = self.output_path.clone();
+        let header = LogHeader::new(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs_f64(),
+            self.address(),
+            self.topics.clone(),
+        );
+
+        // No log file is created when printing to stdout
+        let mut log_file: Option<Box<dyn MessageWriter>> = match &self.output {
+            Output::Print => None,
+            Output::Writer(writer, format) => {
+                Some(format.writer(SharedWriter(writer.clone()), false)?)
+            }
+            Output::Files(files) => Some(files.open(
+                self.output_path.as_ref().expect("Log files have a path"),
+                &file_size,
+                &header,
+            )?),
+        };
+        let files = match &self.output {
+            Output::Files(files) => Some(files),
+            _ => None,
+        };
+
+        let failover = Failover::new(
+            self.servers
+                .iter()
+                .map(|(server, port)| self.mqtt_options(server, *port))
+                .collect::<anyhow::Result<_>>()?,
+            self.servers
+                .iter()
+                .map(|(server, port)| self.address_of(server, *port))
+                .collect(),
+        );
+        let mqtt_options = failover.options[0].clone();
+
+        // Receive notifications on a separate thread so time limits and Ctrl+C are handled even
+        // when no messages are arriving
+        let stalls = Arc::new(AtomicU64::new(0));
+        let metrics = self.metrics_addr.map(|_| Arc::new(Metrics::default()));
+        // Stopped when dropped, also on errors
+        let _metrics_server = match (self.metrics_addr, &metrics) {
+            (Some(addr), Some(metrics)) => Some(MetricsServer::start(addr, metrics.clone())?),
+            _ => None,
+        };
+        let backoff = Arc::new(Mutex::new(Backoff::new(
+            self.reconnect_base,
+            self.reconnect_max,
+        )));
+        let (mut mqtt_client, notification_rx) = if self.async_io {
+            let (client, mut eventloop) = AsyncClient::new(mqtt_options, self.channel_capacity);
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let handle = runtime.handle().clone();
+
+            // Unbounded, so the event loop never waits for the log file to be written
+            let (notification_tx, notification_rx) = mpsc::channel();
+            let backoff = backoff.clone();
+            let metrics = metrics.clone();
+            let failover = failover.clone();
+            thread::spawn(move || {
+                runtime.block_on(async {
+                    loop {
+                        let notification = match eventloop.poll().await {
+                            Err(ConnectionError::RequestsDone) | Err(ConnectionError::Cancel) => {
+                                break
+                            }
+                            notification => notification,
+                        };
+                        let delay = backoff.lock().unwrap().update(&notification);
+                        if notification.is_err() {
+                            failover.next(&mut eventloop.options);
+                        }
+
+                        if let Some(metrics) = &metrics {
+                            metrics.queued();
+                        }
+                        if notification_tx.send(notification).is_err() {
+                            break;
+                        }
+
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                })
+            });
+
+            (MqttClient::Async(client, handle), notification_rx)
+        } else {
+            let (client, mut connection) = Client::new(mqtt_options, self.channel_capacity);
+
+            let (notification_tx, notification_rx) = mpsc::sync_channel(self.channel_capacity);
+            let stalls = stalls.clone();
+            let backoff = backoff.clone();
+            let metrics = metrics.clone();
+            let failover = failover.clone();
+            thread::spawn(move || loop {
+                let mut failed = false;
+                for notification in connection.iter() {
+                    let delay = backoff.lock().unwrap().update(&notification);
+                    failed = notification.is_err();
+
+                    if let Some(metrics) = &metrics {
+                        metrics.queued();
+                    }
+
+                    // Only waiting for room is counted, messages the broker drops meanwhile can not
+                    // be: QoS 0 messages have no packet id, and brokers do not number the ids of a
+                    // subscriber's messages without gaps
+                    match notification_tx.try_send(notification) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(notification)) => {
+                            stalls.fetch_add(1, Ordering::SeqCst);
+                            if notification_tx.send(notification).is_err() {
+                                return;
+                            }
+                        }
+                        Err(TrySendError::Disconnected(_)) => return,
+                    }
+
+                    if let Some(delay) = delay {
+                        thread::sleep(delay);
+                    }
+
+                    // The options can only be changed between iterations of the connection
+                    if failed && failover.options.len() > 1 {
+                        break;
+                    }
+                }
+
+                if !failed {
+                    return;
+                }
+                failover.next(&mut connection.eventloop.options);
+            });
+
+            (MqttClient::Sync(client), notification_rx)
+        };
+
+        for topic in &self.topics {
+            mqtt_client.subscribe(topic, self.qos)?;
+        }
+
+        // Always on stderr, stdout may carry the log records
+        let pb = if self.progress == Progress::Spinner && log_file.is_some() {
+            ProgressBar::with_draw_target(!0, ProgressDrawTarget::stderr())
+        } else {
+            ProgressBar::hidden()
+        };
+        pb.enable_steady_tick(80);
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .tick_strings(&[
+                    "[    ]", "[=   ]", "[==  ]", "[=== ]", "[ ===]", "[  ==]", "[   =]", "[    ]",
+                    "[   =]", "[  ==]", "[ ===]", "[====]", "[=== ]", "[==  ]", "[=   ]",
+                ])
+                .template("{spinner} {msg}"),
+        );
+        pb.set_message("Logging... No messages recorded yet.");
+
+        let mut count: u64 = 0;
+        let mut skipped: u64 = 0;
+        let mut oversized: u64 = 0;
+        // Hashes of the last payload per topic, to keep the memory use low with large payloads
+        let mut last_payloads: HashMap<String, (u64, bool)> = HashMap::new();
+        let mut duplicates: u64 = 0;
+        let mut serialize_errors: u64 = 0;
+        let mut sampler = self.sampling.map(Sampler::new);
+        let mut rate = RollingRate::new(Instant::now());
+        let mut bytes_written = 0.;
+        let mut connected = true;
+        let mut connack_received = false;
+        let mut connected_server = 0;
+        let mut reconnects: u64 = 0;
+        let time_start = SystemTime::now();
+        let mut last_retained = time_start;
+        let rotate_interval = files.and_then(|files| files.rotate_interval);
+        let align_rotation = files.is_some_and(|files| files.align_rotation);
+        let mut last_flush = Instant::now();
+        let mut last_progress_line = Instant::now();
+        let mut rotate_at =
+            rotate_interval.map(|interval| next_rotation(time_start, interval, align_rotation));
+        let frame_interval = files.and_then(|files| files.frame_interval);
+        let mut frames = match (frame_interval, &current_output) {
+            (Some(_), Some(output)) => Some(Frames::open(output, &file_size)?),
+            _ => None,
+        };
+
+        loop {
+            let notification = match notification_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(notification) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.dequeued();
+                    }
+                    Some(notification)
+                }
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            // Refreshed without new messages too, so the rate drops to 0 when traffic stops
+            if rate.update(Instant::now()) && count > 0 {
+                pb.set_message(progress_message(
+                    count,
+                    rate.rate(),
+                    skipped,
+                    reconnects,
+                    bytes_written,
+                    stalls.load(Ordering::SeqCst),
+                ));
+            }
+
+            if shutdown.load(Ordering::SeqCst) {
+                pb.finish();
+                break;
+            }
+
+            if let Some(dur) = self.stop_after {
+                let elapsed = SystemTime::now().duration_since(time_start)?;
+                if elapsed > dur {
+                    self.finish_progress(
+                        &pb,
+                        format!(
+                            "Stopped after {:.1} s, {} messages recorded, uncompressed data size: {:.2} MB.",
+                            elapsed.as_secs_f64(),
+                            count,
+                            bytes_written / 1024. / 1024.,
+                        ),
+                    );
+                    break;
+                }
+            }
+
+            // Retained messages are sent right after subscribing, so silence means all were received
+            if let Some(quiet) = self.snapshot {
+                if SystemTime::now().duration_since(last_retained)? >= quiet {
+                    self.finish_progress(
+                        &pb,
+                        format!("Snapshot complete, {} retained messages recorded.", count),
+                    );
+                    break;
+                }
+            }
+
+            if let (Some(files), Some(interval), Some(at)) = (files, rotate_interval, rotate_at) {
+                let now = SystemTime::now();
+                if now >= at {
+                    rotate_at = Some(next_rotation(now, interval, align_rotation));
+
+                    if let Some(mut finished) = log_file.take() {
+                        // Finish the compressed stream before the next file is started
+                        finished.flush()?;
+                        drop(finished);
+
+                        let output = files.new_path();
+                        log_file = Some(files.open(&output, &file_size, &header)?);
+                        if frames.is_some() {
+                            frames = Some(Frames::open(&output, &file_size)?);
+                        }
+                        current_output = Some(output);
+                        file_number = 0;
+                    }
+                }
+            }
+
+            if let (Progress::Lines(interval), Some(_)) = (self.progress, &log_file) {
+                if last_progress_line.elapsed() >= interval {
+                    eprintln!(
+                        "{}",
+                        progress_message(
+                            count,
+                            rate.rate(),
+                            skipped,
+                            reconnects,
+                            bytes_written,
+                            stalls.load(Ordering::SeqCst),
+                        )
+                    );
+                    last_progress_line = Instant::now();
+                }
+            }
+
+            if let Some(interval) = self.flush_interval {
+                if last_flush.elapsed() >= interval {
+                    if let Some(log_file) = &mut log_file {
+                        if let Err(e) = log_file.flush() {
+                            pb.finish();
+                            return Err(write_error(
+                                e,
+                                log_file.as_mut(),
+                                count,
+                                current_output.as_deref(),
+                            ));
+                        }
+                    }
+                    last_flush = Instant::now();
+                }
+            }
+
+            let notification = match notification {
+                Some(notification) => notification,
+                None => continue,
+            };
+
+            match notification {
+                Ok(Event::Incoming(Incoming::Publish(msg))) => {
+                    if self
+                        .exclude
+                        .iter()
+                        .any(|filter| topic_matches(filter, &msg.topic))
+                    {
+                        trace!("Excluded message on topic '{}'", msg.topic);
+                        continue;
+                    }
+
+                    let included = self
+                        .include_regex
+                        .as_ref()
+                        .is_none_or(|set| set.is_match(&msg.topic));
+                    let excluded = self
+                        .exclude_regex
+                        .as_ref()
+                        .is_some_and(|set| set.is_match(&msg.topic));
+                    if !included || excluded {
+                        trace!("Filtered message on topic '{}'", msg.topic);
+                        continue;
+                    }
+
+                    if self.skip_retained && msg.retain {
+                        trace!("Skipped retained message on topic '{}'", msg.topic);
+                        skipped += 1;
+                        pb.set_message(progress_message(
+                            count,
+                            rate.rate(),
+                            skipped,
+                            reconnects,
+                            bytes_written,
+                            stalls.load(Ordering::SeqCst),
+                        ));
+                        continue;
+                    }
+
+                    if self.skip_empty && msg.payload.is_empty() {
+                        trace!("Skipped empty message on topic '{}'", msg.topic);
+                        continue;
+                    }
+
+                    if let Some(sampler) = &mut sampler {
+                        if !msg.retain && !sampler.keep(&msg.topic) {
+                            trace!("Sampled out message on topic '{}'", msg.topic);
+                            continue;
+                        }
+                    }
+
+                    if self.snapshot.is_some() {
+                        if !msg.retain {
+                            trace!("Ignoring live message on topic '{}'", msg.topic);
+                            continue;
+                        }
+                        last_retained = SystemTime::now();
+                    }
+
+                    if self.dedup {
+                        // A change of the retain flag is logged, even with the same payload
+                        let last = (payload_hash(&msg.payload), msg.retain);
+                        if last_payloads.insert(msg.topic.clone(), last) == Some(last) {
+                            trace!("Suppressed duplicate payload on topic '{}'", msg.topic);
+                            duplicates += 1;
+                            continue;
+                        }
+                    }
+
+                    let mut msg = msg;
+                    let mut orig_len = None;
+                    if let Some((max_bytes, oversize)) = self.max_payload {
+                        if msg.payload.len() > max_bytes {
+                            oversized += 1;
+
+                            if oversize == Oversize::Skip {
+                                debug!(
+                                    "Skipped message of {} bytes on topic '{}'",
+                                    msg.payload.len(),
+                                    msg.topic
+                                );
+                                continue;
+                            }
+
+                            orig_len = Some(msg.payload.len());
+                            msg.payload.truncate(max_bytes);
+                        }
+                    }
+
+                    let now = SystemTime::now();
+                    let time = now.duration_since(SystemTime::UNIX_EPOCH)?.as_secs_f64();
+
+                    count += 1;
+                    rate.add();
+                    let limit_reached = self.max_messages.is_some_and(|max| count >= max);
+
+                    if let (Some(frames), Some(files), Some(interval)) =
+                        (&mut frames, files, frame_interval)
+                    {
+                        let frame_ended = frames
+                            .started
+                            .is_some_and(|started| started.elapsed() >= interval);
+                        if frame_ended {
+                            if let Some(mut finished) = log_file.take() {
+                                // Appending starts a new frame, once the current one is finished
+                                finished.flush()?;
+                                drop(finished);
+
+                                log_file = Some(open_log_file(
+                                    &frames.path,
+                                    files,
+                                    true,
+                                    &file_size,
+                                    &header,
+                                )?);
+                                frames.offset = file_size.load(Ordering::SeqCst);
+                            }
+                        }
+
+                        if frames.started.is_none() || frame_ended {
+                            frames.index.add(&IndexEntry {
+                                offset: frames.offset,
+                                time,
+                                messages: frames.messages,
+                            })?;
+                            frames.started = Some(Instant::now());
+                        }
+                        frames.messages += 1;
+                    }
+
+                    let log_file = match &mut log_file {
+                        Some(log_file) => log_file,
+                        None => {
+                            // Show text as is, and binary data as hex
+                            let payload = match std::str::from_utf8(&msg.payload) {
+                                Ok(text) => Cow::Borrowed(text),
+                                Err(_) => Cow::Owned(
+                                    msg.payload.iter().map(|b| format!("{:02x}", b)).collect(),
+                                ),
+                            };
+
+                            let topic = if self.color {
+                                Cow::Owned(format!("\x1b[1;36m{}\x1b[0m", msg.topic))
+                            } else {
+                                Cow::Borrowed(msg.topic.as_str())
+                            };
+
+                            println!(
+                                "{:.6} {} {} {} {}",
+                                time, topic, msg.qos as u8, msg.retain, payload
+                            );
+
+                            if limit_reached {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+
+                    let (dup, pkid) = (msg.dup, msg.pkid);
+                    let mut msg = MqttMessage::from_publish(
+                        msg,
+                        self.time_format.time(now, self.time_zone),
+                        self.text_payloads,
+                        self.payload_encoding,
+                    );
+                    if self.sequence {
+                        msg.seq = Some(count - 1);
+                    }
+                    if self.protocol_fields {
+                        msg.dup = Some(dup);
+                        msg.pkid = Some(pkid);
+                    }
+                    msg.truncated = orig_len.map(|_| true);
+                    msg.orig_len = orig_len;
+
+                    let written = match log_file.write_message(&msg) {
+                        Ok(written) => {
+                            // Only messages in the log are counted, not printed or failed ones
+                            if let Some(metrics) = &metrics {
+                                metrics.message(&msg.topic);
+                                metrics.bytes(written as u64);
+                            }
+                            written
+                        }
+                        Err(e) if e.is::<SerializeError>() => {
+                            error!(
+                                "Unable to serialize the message on topic '{}': {}",
+                                msg.topic, e
+                            );
+                            serialize_errors += 1;
+                            0
+                        }
+                        Err(e) => {
+                            pb.finish();
+                            return Err(write_error(
+                                e,
+                                log_file.as_mut(),
+                                count - 1,
+                                current_output.as_deref(),
+                            ));
+                        }
+                    };
+                    bytes_written += written as f64;
+
+                    if let Some((files, max_size)) =
+                        files.and_then(|f| f.max_size.map(|max| (f, max)))
+                    {
+                        if file_size.load(Ordering::SeqCst) >= max_size {
+                            file_number += 1;
+                            let output = numbered_path(
+                                current_output.as_ref().unwrap(),
+                                file_number,
+                                &files.extension(),
+                            );
+                            debug!(
+                                "Maximum file size reached, continuing in '{}'",
+                                output.display()
+                            );
+
+                            // Replacing the writer finishes the compressed stream of the previous file
+                            log_file.flush()?;
+                            *log_file = files.open(&output, &file_size, &header)?;
+                            if frames.is_some() {
+                                frames = Some(Frames::open(&output, &file_size)?);
+                            }
+                        }
+                    }
+
+                    pb.set_message(progress_message(
+                        count,
+                        rate.rate(),
+                        skipped,
+                        reconnects,
+                        bytes_written,
+                        stalls.load(Ordering::SeqCst),
+                    ));
+
+                    if limit_reached {
+                        self.finish_progress(
+                            &pb,
+                            format!(
+                                "Stopped after reaching the limit of {} messages, uncompressed data size: {:.2} MB.",
+                                count,
+                                bytes_written / 1024. / 1024.,
+                            ),
+                        );
+                        break;
+                    }
+                }
+                Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                    if connack_received {
+                        reconnects += 1;
+                        if let Some(metrics) = &metrics {
+                            metrics.reconnect();
+                        }
+                    }
+                    connack_received = true;
+
+                    let server = failover.current.load(Ordering::SeqCst);
+                    if server != connected_server {
+                        let message =
+                            format!("Connected to the broker at '{}'", failover.address());
+                        if pb.is_hidden() {
+                            eprintln!("{}", message);
+                        } else {
+                            pb.println(message);
+                        }
+                        connected_server = server;
+                    }
+                }
+                Ok(Event::Incoming(Incoming::SubAck(_))) => {
+                    // The quiet period of --snapshot starts once subscribed
+                    last_retained = SystemTime::now();
+                }
+                Ok(Event::Incoming(Incoming::Disconnect)) => {
+                    debug!("Disconnected, trying to reconnect...");
+                    connected = false;
+                }
+                Ok(Event::Outgoing(Outgoing::PingReq)) => {
+                    if !connected {
+                        debug!("Trying to resubscribe...");
+
+                        for topic in &self.topics {
+                            mqtt_client.subscribe(topic, self.qos)?;
+                        }
+
+                        connected = true;
+                    }
+                }
+                Ok(val) => trace!("Unhandled Ok(...) notification: {:?}", val),
+                Err(val) => {
+                    match val {
+                        ConnectionError::MqttState(e) => {
+                            debug!("MQTT error, will try to reconnect when possible: {:?}", e);
+                            connected = false;
+                        }
+                        // The broker refusing the connection (e.g. bad credentials) will not fix itself
+                        ConnectionError::Io(e)
+                            if e.kind() == ErrorKind::InvalidData
+                                && e.to_string().starts_with("Broker rejected") =>
+                        {
+                            pb.finish();
+                            if let Some(log_file) = &mut log_file {
+                                log_file.flush()?;
+                            }
+                            return Err(anyhow!("Connection refused: {}", e));
+                        }
+                        // A broker rejecting the client certificate aborts the handshake with a TLS
+                        // alert, which would otherwise look like any other network error
+                        ConnectionError::Network(e)
+                            if self.client_auth.is_some() && tls_alert_received(&e) =>
+                        {
+                            pb.finish();
+                            if let Some(log_file) = &mut log_file {
+                                log_file.flush()?;
+                            }
+                            return Err(anyhow!(
+                            "TLS handshake rejected by the broker, check the client certificate: {:?}",
+                            e
+                        ));
+                        }
+                        ConnectionError::Network(e) => {
+                            debug!(
+                                "Network error, will try to reconnect when possible: {:?}",
+                                e
+                            );
+                            connected = false;
+                        }
+                        _ => {
+                            trace!("Unhandled Err(...) notification: {:?}", val);
+                            connected = false;
+                        }
+                    }
+
+                    if let Some(max) = self.max_reconnects {
+                        let attempts = backoff.lock().unwrap().attempt();
+                        if attempts > max {
+                            pb.finish();
+                            if let Some(log_file) = &mut log_file {
+                                log_file.flush()?;
+                            }
+                            return Err(anyhow!(
+                                "Giving up after {} failed attempts to reconnect to the broker",
+                                max
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(log_file) = &mut log_file {
+            if let Err(e) = log_file.flush() {
+                return Err(write_error(
+                    e,
+                    log_file.as_mut(),
+                    count,
+                    current_output.as_deref(),
+                ));
+            }
+        }
+
+        let downtime = backoff.lock().unwrap().downtime();
+        if self.progress != Progress::Off && log_file.is_some() && downtime > Duration::ZERO {
+            eprintln!(
+                "Disconnected from the broker for {:.1} s in total",
+                downtime.as_secs_f64()
+            );
+        }
+
+        let stalls = stalls.load(Ordering::SeqCst);
+        if stalls > 0 {
+            eprintln!(
+                "Warning: receiving waited {} times for the log to catch up with a full \
+                 --channel-capacity. Messages are not lost by waiting, but a broker may drop QoS 0 \
+                 messages to a client which reads slowly. Consider a larger --channel-capacity or \
+                 --async-io.",
+                stalls
+            );
+        }
+
+        if duplicates > 0 {
+            eprintln!(
+                "{} messages repeated the last payload of their topic and were suppressed",
+                duplicates
+            );
+        }
+
+        if serialize_errors > 0 {
+            eprintln!(
+                "{} messages could not be serialized and were dropped",
+                serialize_errors
+            );
+        }
+
+        if let Some((max_bytes, oversize)) = self.max_payload {
+            if oversized > 0 {
+                eprintln!(
+                    "{} messages had a payload above {} bytes and were {}",
+                    oversized,
+                    max_bytes,
+                    match oversize {
+                        Oversize::Skip => "skipped",
+                        Oversize::Truncate => "truncated",
+                    }
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The message rate over the last completed window of one second.
+struct RollingRate {
+    window_start: Instant,
+    count: u64,
+    rate: f64,
+}
+
+impl RollingRate {
+    fn new(now: Instant) -> Self {
+        RollingRate {
+            window_start: now,
+            count: 0,
+            rate: 0.,
+        }
+    }
+
+    fn add(&mut self) {
+        self.count += 1;
+    }
+
+    /// Completes the window once a second has passed, returns true if the rate was updated.
+    fn update(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+
+        self.rate = self.count as f64 / elapsed.as_secs_f64();
+        self.count = 0;
+        self.window_start = now;
+
+        true
+    }
+
+    fn rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+/// The connection options of each broker, the next broker is tried after a connection error.
+/// Clones share the current broker.
+#[derive(Clone)]
+struct Failover {
+    options: Vec<MqttOptions>,
+    addresses: Vec<String>,
+    /// The broker connected to, or being connected to
+    current: Arc<AtomicUsize>,
+}
+
+impl Failover {
+    fn new(options: Vec<MqttOptions>, addresses: Vec<String>) -> Self {
+        Failover {
+            options,
+            addresses,
+            current: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Switches to the options of the next broker, if there are several.
+    fn next(&self, options: &mut MqttOptions) {
+        if self.options.len() < 2 {
+            return;
+        }
+
+        let next = (self.current.load(Ordering::SeqCst) + 1) % self.options.len();
+        self.current.store(next, Ordering::SeqCst);
+        *options = self.options[next].clone();
+        debug!("Trying the broker at '{}'", self.addresses[next]);
+    }
+
+    fn address(&self) -> &str {
+        &self.addresses[self.current.load(Ordering::SeqCst)]
+    }
+}
+
+/// Exponential backoff between reconnection attempts, which also tracks the time spent
+/// disconnected.
+struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    down_since: Option<Instant>,
+    downtime: Duration,
+}
+
+impl Backoff {
+    fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+            down_since: None,
+            downtime: Duration::ZERO,
+        }
+    }
+
+    /// Returns the delay before the next connection attempt after a connection error.
+    fn update(&mut self, notification: &Result<Event, ConnectionError>) -> Option<Duration> {
+        match notification {
+            Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                if let Some(since) = self.down_since.take() {
+                    self.downtime += since.elapsed();
+                }
+                self.attempt = 0;
+                None
+            }
+            Err(_) => {
+                self.down_since.get_or_insert_with(Instant::now);
+
+                // Doubles for every attempt, minus up to half of it as jitter
+                let delay = self
+                    .base
+                    .saturating_mul(1 << self.attempt.min(16))
+                    .min(self.max);
+                let nanos = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .subsec_nanos();
+                let delay = delay - delay / 2 * (nanos % 1000) / 1000;
+
+                self.attempt += 1;
+                debug!(
+                    "Reconnection attempt {} in {} ms",
+                    self.attempt,
+                    delay.as_millis()
+                );
+
+                Some(delay)
+            }
+            _ => None,
+        }
+    }
+
+    /// Connection errors since the last successful connection.
+    fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// The total time spent disconnected, including an ongoing disconnection.
+    fn downtime(&self) -> Duration {
+        self.downtime
+            + self
+                .down_since
+                .map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// The sync client, or the async client with a handle to the runtime of its event loop.
+enum MqttClient {
+    Sync(Client),
+    Async(AsyncClient, tokio::runtime::Handle),
+}
+
+impl MqttClient {
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+        match self {
+            MqttClient::Sync(client) => client.subscribe(topic, qos),
+            MqttClient::Async(client, runtime) => runtime.block_on(client.subscribe(topic, qos)),
+        }
+    }
+}
+
+/// Writes into a writer shared with the creator of the logger.
+struct SharedWriter(Arc<Mutex<dyn Write + Send>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("Poisoned writer").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().expect("Poisoned writer").flush()
+    }
+}
+
+/// Loads and validates a client certificate and private key for mutual TLS.
+pub fn load_client_auth(cert_path: &Path, key_path: &Path) -> anyhow::Result<(Vec<u8>, Key)> {
+    let cert = fs::read(cert_path).with_context(|| {
+        format!(
+            "Could not read client certificate '{}'",
+            cert_path.display()
+        )
+    })?;
+    let key = fs::read(key_path)
+        .with_context(|| format!("Could not read client key '{}'", key_path.display()))?;
+
+    match rumqttc::certs(&mut Cursor::new(&cert)) {
+        Ok(certs) if !certs.is_empty() => {}
+        _ => {
+            return Err(anyhow!(
+                "No valid PEM certificates found in client certificate '{}'",
+                cert_path.display()
+            ))
+        }
+    }
+
+    // rumqttc names PKCS#8 keys `ECC`, but they may hold any key type
+    let key = match rumqttc::pkcs8_private_keys(&mut Cursor::new(&key)) {
+        Ok(keys) if !keys.is_empty() => Key::ECC(key),
+        _ => match rumqttc::rsa_private_keys(&mut Cursor::new(&key)) {
+            Ok(keys) if !keys.is_empty() => Key::RSA(key),
+            _ => {
+                return Err(anyhow!(
+                    "No valid PKCS#8 or RSA PEM private key found in '{}'",
+                    key_path.display()
+                ))
+            }
+        },
+    };
+
+    // Let rustls check that the key is usable with the certificate before connecting
+    set_client_auth(&mut ClientConfig::new(), &cert, &key).with_context(|| {
+        format!(
+            "Invalid client certificate '{}' or key '{}'",
+            cert_path.display(),
+            key_path.display()
+        )
+    })?;
+
+    Ok((cert, key))
+}
+
+/// Adds the client certificate chain and key to a rustls configuration.
+fn set_client_auth(client_config: &mut ClientConfig, cert: &[u8], key: &Key) -> anyhow::Result<()> {
+    let certs = rumqttc::certs(&mut Cursor::new(cert)).unwrap_or_default();
+    let mut keys = match key {
+        Key::RSA(k) => rumqttc::rsa_private_keys(&mut Cursor::new(k)),
+        Key::ECC(k) => rumqttc::pkcs8_private_keys(&mut Cursor::new(k)),
+    }
+    .unwrap_or_default();
+
+    if certs.is_empty() || keys.is_empty() {
+        return Err(anyhow!("Missing client certificate or key"));
+    }
+
+    client_config
+        .set_single_client_cert(certs, keys.remove(0))
+        .map_err(|e| anyhow!("Client certificate and key do not match: {}", e))
+}
+
+/// Creates a new compressed log file, failing if it already exists unless appending. A reopened
+/// file was finished by this logger before, and is appended to without checking it.
+fn open_log_file(
+    path: &Path,
+    files: &LogFiles,
+    reopen: bool,
+    file_size: &Arc<AtomicU64>,
+    header: &LogHeader,
+) -> anyhow::Result<Box<dyn MessageWriter>> {
+    let codec = files.codec;
+    let append = reopen || (files.append && path.exists());
+
+    if append && !reopen {
+        // Concatenated streams decode as one, but only if the existing ones are intact
+        let mut decoder = codec.decoder(fs::File::open(path)?)?;
+        io::copy(&mut decoder, &mut io::sink()).with_context(|| {
+            format!(
+                "Can not append to '{}', it is not a valid {:?} stream",
+                path.display(),
+                codec
+            )
+        })?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).create_new(true);
+    }
+
+    let file = options.open(path)?;
+    file_size.store(file.metadata()?.len(), Ordering::SeqCst);
+    let file: Box<dyn Write> = if files.fsync {
+        Box::new(SyncOnFlush::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let log_file = BufWriter::with_capacity(
+        files.buffer_size,
+        CountingWriter::new(file, file_size.clone()),
+    );
+
+    let mut writer = files.format.writer(
+        codec.encoder(log_file, files.compression_level, files.compression_threads)?,
+        append,
+    )?;
+    if files.header && !append {
+        writer.write_header(header)?;
+    }
+
+    Ok(writer)
+}
+
+/// Whether a connection error comes from a TLS alert sent by the broker, which aborts the
+/// handshake. The alert is wrapped in I/O errors, which do not give it as their source.
+fn tls_alert_received(e: &(dyn std::error::Error + 'static)) -> bool {
+    let mut error = Some(e);
+    while let Some(e) = error {
+        if let Some(TLSError::AlertReceived(_)) = e.downcast_ref::<TLSError>() {
+            return true;
+        }
+
+        error = match e.downcast_ref::<io::Error>() {
+            Some(e) => e.get_ref().map(|e| e as &(dyn std::error::Error + 'static)),
+            None => e.source(),
+        };
+    }
+
+    false
+}
+
+/// The error of a failed write to the log file, after a last attempt to flush the messages
+/// logged before it.
+fn write_error(
+    e: anyhow::Error,
+    log_file: &mut dyn MessageWriter,
+    logged: u64,
+    output: Option<&Path>,
+) -> anyhow::Error {
+    let output = match output {
+        Some(path) => format!("'{}'", path.display()),
+        None => "stdout".to_string(),
+    };
+    let logged = match log_file.flush() {
+        Ok(()) => format!("flushed {} messages to {}", logged, output),
+        Err(_) => format!(
+            "{} messages were logged to {}, the last of them may be lost",
+            logged, output
+        ),
+    };
+
+    let storage_full = e
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::StorageFull);
+    if storage_full {
+        anyhow!("The disk is full, {}", logged)
+    } else {
+        anyhow!("Unable to write the log ({}), {}", e, logged)
+    }
+}
+
+fn payload_hash(payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The spinner message while logging, skipped messages and stalls are only shown if any.
+fn progress_message(
+    count: u64,
+    rate: f64,
+    skipped: u64,
+    reconnects: u64,
+    bytes_written: f64,
+    stalls: u64,
+) -> String {
+    let mut message = format!(
+        "Logging... {} messages recorded ({:.1} msg/s), uncompressed data size: {:.2} MB.",
+        count,
+        rate,
+        bytes_written / 1024. / 1024.,
+    );
+
+    if skipped > 0 {
+        message += &format!(" {} retained messages skipped.", skipped);
+    }
+
+    if reconnects > 0 {
+        message += &format!(" Reconnected {} times.", reconnects);
+    }
+
+    if stalls > 0 {
+        message += &format!(" Receiving waited for the log {} times.", stalls);
+    }
+
+    message
+}
+
+/// Numbers a log file path, e.g. `output.json.zst` becomes `output.0001.json.zst`.
+fn numbered_path(path: &Path, number: u32, extension: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .expect("Non-unicode path?");
+    let stem = name
+        .strip_suffix(&format!(".{}", extension))
+        .unwrap_or(name);
+
+    path.with_file_name(format!("{}.{:04}.{}", stem, number, extension))
+}
+
+/// Parses a size in bytes with an optional K, M or G suffix (powers of 1024), e.g. 100MB.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size '{}'", s))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid size unit in '{}', expected K, M or G", s)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Sets the log file extension, unless the path already has it.
+fn set_log_extension(path: &mut PathBuf, extension: &str) {
+    let has_extension = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(&format!(".{}", extension)));
+
+    if !has_extension {
+        path.set_extension(extension);
+    }
+}
+
+/// The time of the next rotation, on the next multiple of the interval since the epoch if aligned.
+fn next_rotation(now: SystemTime, interval: Duration, aligned: bool) -> SystemTime {
+    if !aligned {
+        return now + interval;
+    }
+
+    let interval = interval.as_secs().max(1);
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    SystemTime::UNIX_EPOCH + Duration::from_secs((since_epoch / interval + 1) * interval)
+}
+
+/// Adds the UTC `time` to the file name before the log extension, for files rotated by time.
+fn timestamped_path(output: &Path, extension: &str, time: DateTime<Utc>) -> PathBuf {
+    let filename = output
+        .file_name()
+        .expect("Empty filename?")
+        .to_str()
+        .expect("Non-unicode path?");
+    // The time goes before the log extension, e.g. out.json.zst or out.json for out.json.zst
+    let format_extension = extension.split('.').next().unwrap_or(extension);
+    let stem = [extension, format_extension]
+        .iter()
+        .find_map(|extension| filename.strip_suffix(&format!(".{}", extension)))
+        .unwrap_or(filename);
+
+    output.with_file_name(format!(
+        "{}-{}.{}",
+        stem,
+        time.format("%Y%m%dT%H%M%S"),
+        extension
+    ))
+}
+
+/// Checks if a topic matches a topic filter, with `+` matching a single level and `#` matching
+/// any number of levels.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the first level do not match topics starting with '$', e.g. $SYS
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Compiles the patterns into a set, no patterns give `None`.
+fn regex_set(patterns: &[String]) -> anyhow::Result<Option<RegexSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(RegexSet::new(patterns)?))
+}
+
+pub fn parse_qos(s: &str) -> anyhow::Result<QoS> {
+    match s {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err(anyhow!("QoS needs to be 0, 1 or 2, got '{}'", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone as _;
+
+    #[test]
+    fn timestamped_path_keeps_the_log_extension() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 7, 13, 0, 0).unwrap();
+        let timestamped =
+            |output: &str, extension: &str| timestamped_path(Path::new(output), extension, time);
+
+        assert_eq!(
+            timestamped("out.json.zst", "json.zst"),
+            Path::new("out-20240107T130000.json.zst")
+        );
+        assert_eq!(
+            timestamped("out.json", "json.zst"),
+            Path::new("out-20240107T130000.json.zst")
+        );
+        assert_eq!(
+            timestamped("logs/out", "csv"),
+            Path::new("logs/out-20240107T130000.csv")
+        );
+        assert_eq!(
+            timestamped("site.a.json.zst", "json.zst"),
+            Path::new("site.a-20240107T130000.json.zst")
+        );
+        assert_ne!(
+            timestamped("out.json.zst", "json.zst"),
+            timestamped_path(
+                Path::new("out.json.zst"),
+                "json.zst",
+                time + chrono::Duration::seconds(1)
+            )
+        );
+    }
+
+    #[test]
+    fn topic_matches_wildcards() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("+/+", "a/b"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/+/c", "a/c"));
+
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(!topic_matches("a/#", "b/c"));
+        // `#` also matches the parent level itself
+        assert!(topic_matches("a/#", "a"));
+    }
+
+    #[test]
+    fn topic_matches_level_counts() {
+        assert!(!topic_matches("a/b", "a"));
+        assert!(!topic_matches("a", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn topic_matches_system_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn host_of_server_addresses() {
+        assert_eq!(host("192.168.1.10").unwrap(), "192.168.1.10");
+        assert_eq!(host("broker.local").unwrap(), "broker.local");
+        assert_eq!(host("::1").unwrap(), "::1");
+        assert_eq!(host("2001:db8::1").unwrap(), "2001:db8::1");
+        assert_eq!(host("[2001:db8::1]").unwrap(), "2001:db8::1");
+
+        // The port is given with --port
+        assert!(host("[2001:db8::1]:1883").is_err());
+        assert!(host("broker.local:1883").is_err());
+        assert!(host("192.168.1.10:1883").is_err());
+
+        assert!(host("[2001:db8::1").is_err());
+        assert!(host("[]").is_err());
+        assert!(host("").is_err());
+    }
+
+    #[test]
+    fn tls_alerts_of_connection_errors() {
+        use rustls::internal::msgs::enums::AlertDescription;
+
+        let alert = || TLSError::AlertReceived(AlertDescription::BadCertificate);
+        let is_alert = |e: ConnectionError| match e {
+            ConnectionError::Network(e) => tls_alert_received(&e),
+            _ => unreachable!(),
+        };
+
+        assert!(is_alert(ConnectionError::Network(alert().into())));
+        // The handshake gives rustls errors as the payload of I/O errors
+        assert!(is_alert(ConnectionError::Network(
+            io::Error::new(ErrorKind::InvalidData, alert()).into()
+        )));
+
+        assert!(!is_alert(ConnectionError::Network(
+            TLSError::HandshakeNotComplete.into()
+        )));
+        assert!(!is_alert(ConnectionError::Network(
+            io::Error::from(ErrorKind::ConnectionRefused).into()
+        )));
+    }
+}
@@ -1,35 +1,24 @@
-use anyhow::anyhow;
-use chrono::{DateTime, SecondsFormat, Utc};
-use indicatif::{ProgressBar, ProgressStyle};
+use anyhow::{anyhow, Context};
 use log::*;
-use rumqttc::{
-    Client, ClientConfig, ConnectionError, Event, Incoming, MqttOptions, Outgoing, QoS,
-    TlsConfiguration, Transport,
+use mqtt_logger::codec::Codec;
+use mqtt_logger::writer::Format;
+use mqtt_logger::{
+    parse_qos, parse_size, LogFiles, Logger, Output, Oversize, PayloadEncoding, Progress,
+    SampleScope, Sampling, TimeFormat, TimeZone,
 };
-use serde::Serialize;
+use rumqttc::QoS;
 use simple_logger::SimpleLogger;
-use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{self, Cursor, IsTerminal};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::time::SystemTime;
 use structopt::StructOpt;
 
-// Reference:
-// {"time": 1611137748.0325797, "qos": 0, "retain": true, "topic": "kvarntorp-test/gateway/165640a7e023861a/nodeversion", "msg_b64": "IjAuMi4xNSI="}
-
-#[derive(Serialize, Debug)]
-struct MqttMessage {
-    time: f64,
-    qos: u8,
-    retain: bool,
-    topic: String,
-    msg_b64: String,
-}
-
 #[derive(Debug, StructOpt)]
 #[structopt(name = "mqtt-logger", about = "A logger of an entire MQTT stream")]
 struct Opt {
@@ -37,93 +26,647 @@ struct Opt {
     #[structopt(short, long, env = "VERBOSITY", default_value = "0")]
     verbosity: u32,
 
-    /// Output log file
-    #[structopt(env = "OUTPUT", parse(from_os_str))]
-    output: PathBuf,
-
-    /// ZSTD compression level
-    #[structopt(short, long, env = "COMPRESSION_LEVEL", default_value = "9")]
-    compression_level: i32,
+    /// Read options from a TOML file, keyed by their long names, e.g. `server = "broker"` or
+    /// `topic = ["a/#", "b/#"]`. Options given on the command line take precedence over the file,
+    /// which takes precedence over environment variables
+    #[structopt(long, env = "CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
 
-    /// Server address
+    /// Output log file, required unless printing. `-` is the same as --stdout
+    #[structopt(env = "OUTPUT", parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Print incoming messages to stdout instead of logging them to a file
+    #[structopt(long)]
+    print: bool,
+
+    /// Watch the incoming messages instead of logging them, printed like --print with colored
+    /// topics. Colors are off when stdout is not a terminal or NO_COLOR is set
+    #[structopt(long, conflicts_with = "stdout")]
+    tail: bool,
+
+    /// Write the uncompressed log records to stdout instead of a file, e.g. to pipe JSON lines
+    /// into jq. Progress goes to stderr as usual
+    #[structopt(long, conflicts_with = "print")]
+    stdout: bool,
+
+    /// Log record format: json, csv or msgpack (length prefixed MessagePack with raw payloads)
+    #[structopt(long, env = "FORMAT", default_value = "json")]
+    format: Format,
+
+    /// Store UTF-8 payloads as text in a `msg` field, other payloads use --payload-encoding
+    #[structopt(long, alias = "decode-text")]
+    text_payloads: bool,
+
+    /// Encoding of binary payloads: base64 (msg_b64 field) or hex (msg_hex field)
+    #[structopt(long, env = "PAYLOAD_ENCODING", default_value = "base64")]
+    payload_encoding: PayloadEncoding,
+
+    /// Time of received messages: unix (seconds in `time`), unix-millis (milliseconds in
+    /// `time_ms`) or rfc3339 (timestamp in `time_iso`, along with `time` in seconds). epoch and
+    /// iso8601 are accepted as aliases of unix and rfc3339
+    #[structopt(long, env = "TIME_FORMAT", default_value = "unix")]
+    time_format: TimeFormat,
+
+    /// Time zone of rfc3339 timestamps: utc, local or an offset like +02:00
+    #[structopt(long, env = "TIMEZONE", default_value = "utc")]
+    timezone: TimeZone,
+
+    /// Compression of the log file: zstd, gzip, lz4 or none
+    #[structopt(long, alias = "compression", env = "CODEC", default_value = "zstd")]
+    codec: Codec,
+
+    /// Compression level, 9 if not given. Only used by zstd (up to 22) and gzip (0-9)
+    #[structopt(short, long, env = "COMPRESSION_LEVEL")]
+    compression_level: Option<i32>,
+
+    /// Compress on this many threads, for message rates a single core can not keep up with.
+    /// Only used by zstd
+    #[structopt(long, env = "COMPRESSION_THREADS", default_value = "1")]
+    compression_threads: u32,
+
+    /// Server address, a hostname or an IPv4 or IPv6 address, e.g. `::1` or `[2001:db8::1]`. The
+    /// port is given with --port. Given multiple times, the next server is tried when connecting
+    /// fails or the connection drops
     #[structopt(short, long, env = "SERVER", default_value = "localhost")]
-    server: String,
+    server: Vec<String>,
 
-    /// Server port
+    /// Server port, either one for all servers or one per --server in the same order
     #[structopt(short, long, env = "PORT")]
-    port: Option<u16>,
+    port: Vec<u16>,
 
-    /// TLS enable
-    #[structopt(long, env = "TLS")]
+    /// TLS enable, uses the system root certificates unless a custom CA is given. Also enabled by
+    /// the environment variable TLS=true
+    #[structopt(long)]
     tls: bool,
 
-    /// Path to custom CA file
-    #[structopt(long, env = "CUSTOM_CA")]
+    /// Connect over a WebSocket, the server and port are those of the WebSocket endpoint
+    #[structopt(long)]
+    ws: bool,
+
+    /// Connect over a secure WebSocket, implies TLS
+    #[structopt(long)]
+    wss: bool,
+
+    /// URL path of the WebSocket endpoint
+    #[structopt(long, env = "WS_PATH", default_value = "/mqtt")]
+    ws_path: String,
+
+    /// Path to custom CA file (PEM), implies TLS
+    #[structopt(long, alias = "ca-cert", env = "CUSTOM_CA")]
     custom_ca: Option<PathBuf>,
 
-    /// An optional duration for how long to log, e.g. 100s, 12h, 1year, etc.
-    #[structopt(long, required_if("forever", "true"), env = "DURATION")]
+    /// Path to client certificate (PEM) for mutual TLS, requires --client-key
+    #[structopt(long, env = "CLIENT_CERT")]
+    client_cert: Option<PathBuf>,
+
+    /// Path to the private key (PEM, PKCS#8 or RSA) belonging to --client-cert
+    #[structopt(long, env = "CLIENT_KEY")]
+    client_key: Option<PathBuf>,
+
+    /// MQTT client id, a unique one is generated if not given
+    #[structopt(long, env = "CLIENT_ID")]
+    client_id: Option<String>,
+
+    /// Start a clean session. With false the broker keeps the session of --client-id between
+    /// connections, and queues QoS 1 and 2 messages while the logger is disconnected, which it
+    /// delivers on reconnect. Subscribe with --qos 1 or 2 for a capture without gaps, QoS 0
+    /// messages are not queued
+    #[structopt(
+        long,
+        env = "CLEAN_SESSION",
+        default_value = "true",
+        parse(try_from_str)
+    )]
+    clean_session: bool,
+
+    /// Keep the session between connections, the same as --clean-session false. Requires a
+    /// fixed --client-id
+    #[structopt(long)]
+    persistent_session: bool,
+
+    /// Username for authenticating against the server, requires --password
+    #[structopt(long, env = "USERNAME")]
+    username: Option<String>,
+
+    /// Password for authenticating against the server, requires --username
+    #[structopt(long, env = "PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+
+    /// Read the password from a file instead, keeping it out of the process list
+    #[structopt(long, env = "PASSWORD_FILE", conflicts_with = "password")]
+    password_file: Option<PathBuf>,
+
+    /// An optional duration for how long to log, e.g. 30 (seconds), 100s, 12h, 1year, etc.
+    #[structopt(long, alias = "max-duration", env = "DURATION")]
     duration: Option<String>,
 
-    /// If this is set it will log and save a new file with the period set by duration.
-    #[structopt(long, env = "FOREVER")]
+    /// If this is set it will log and save a new file with the period set by duration. Also
+    /// enabled by the environment variable FOREVER=true
+    #[structopt(long)]
     forever: bool,
 
-    /// Topic to subscribe to. Supports multiple.
+    /// Start a new timestamped log file with this interval, e.g. 1h or 30m, named like
+    /// out-20240107T130000.json.zst in UTC. Files are started on multiples of the interval in
+    /// UTC, e.g. on the hour for 1h. Together with --max-size a new file is started by whichever
+    /// limit is reached first.
+    #[structopt(long, env = "ROTATE_INTERVAL")]
+    rotate_interval: Option<String>,
+
+    /// Start a new numbered log file when the compressed file reaches this size, e.g. 100MB.
+    /// Files may overshoot the size by the write buffers.
+    #[structopt(
+        long,
+        alias = "rotate-size",
+        env = "MAX_SIZE",
+        parse(try_from_str = parse_size)
+    )]
+    max_size: Option<u64>,
+
+    /// Start a new compressed frame of the log file with this interval, e.g. 1m, and list the
+    /// frames with their time in an index next to it, e.g. log.json.zst.idx. Tools reading a
+    /// time range skip to the right frame instead of decompressing from the start
+    #[structopt(long, env = "FRAME_INTERVAL")]
+    frame_interval: Option<String>,
+
+    /// Write the index of --frame-interval, with frames of 1m unless another interval is given.
+    /// mqtt-repair rebuilds the index of a repaired log
+    #[structopt(long)]
+    index: bool,
+
+    /// Write one log file per prefix of this many topic levels into the output directory, e.g. 1
+    /// logs both sensors/a and sensors/b into sensors.json.zst
+    #[structopt(
+        long,
+        env = "SPLIT_BY_TOPIC",
+        conflicts_with_all = &["max-size", "rotate-interval", "forever"]
+    )]
+    split_by_topic: Option<usize>,
+
+    /// Size of the write buffer of each log file, e.g. 64K or 16M. Smaller buffers save memory
+    /// on small devices, larger ones mean fewer writes at high message rates. The buffer holds
+    /// compressed data, so its size does not change the compression ratio, frequent flushes with
+    /// --flush-interval do
+    #[structopt(
+        long,
+        env = "BUFFER_SIZE",
+        default_value = "128K",
+        parse(try_from_str = parse_size)
+    )]
+    buffer_size: u64,
+
+    /// Start each new JSON log file with a header record of the capture: the logger version,
+    /// start time, broker and topics. Readers of this repository skip it
+    #[structopt(long, env = "HEADER", default_value = "true", parse(try_from_str))]
+    header: bool,
+
+    /// The maximum number of log files open at the same time with --split-by-topic. Beyond it the
+    /// least recently written file is closed, and appended to when its topics show up again
+    #[structopt(long, env = "MAX_OPEN_FILES", default_value = "64")]
+    max_open_files: usize,
+
+    /// Flush the log with this interval, e.g. 5s, so a crash loses at most this much of the log.
+    /// Without it data is written when the buffers fill up. Short intervals compress worse and
+    /// cost throughput
+    #[structopt(long, env = "FLUSH_INTERVAL")]
+    flush_interval: Option<String>,
+
+    /// Also sync the log file to disk when flushing, to survive power loss at the cost of
+    /// waiting for the disk
+    #[structopt(long)]
+    fsync: bool,
+
+    /// Exit with an error after this many consecutive failed attempts to reconnect to the
+    /// broker, instead of retrying forever
+    #[structopt(long, env = "MAX_RECONNECTS")]
+    max_reconnects: Option<u32>,
+
+    /// Do not show any progress while logging
+    #[structopt(long)]
+    no_progress: bool,
+
+    /// How often to print a progress line when stderr is not a terminal, e.g. under systemd
+    #[structopt(long, env = "PROGRESS_INTERVAL", default_value = "60s")]
+    progress_interval: String,
+
+    /// Stop after this many messages have been recorded
+    #[structopt(long, env = "MAX_MESSAGES")]
+    max_messages: Option<u64>,
+
+    /// Record the retained messages on the broker and exit once no more arrive within
+    /// --quiet-period, live messages are ignored
+    #[structopt(long)]
+    snapshot: bool,
+
+    /// Drop retained messages, e.g. the ones the broker sends when subscribing, so only live
+    /// traffic is recorded
+    #[structopt(long, conflicts_with = "snapshot")]
+    skip_retained: bool,
+
+    /// Drop messages with an empty payload. Note that an empty retained publish clears the
+    /// retained message of its topic, so the log no longer shows when retained state was removed
+    #[structopt(long)]
+    skip_empty: bool,
+
+    /// Add a `seq` field to JSON records, counting the messages in arrival order from 0. Orders
+    /// records with identical timestamps, CSV rows are in arrival order already
+    #[structopt(long)]
+    sequence: bool,
+
+    /// Add the `dup` flag and packet identifier `pkid` of each publish to JSON records, to debug
+    /// QoS 1 and 2 redeliveries
+    #[structopt(long)]
+    protocol_fields: bool,
+
+    /// Drop messages whose payload is identical to the last one logged on the same topic, e.g.
+    /// devices republishing unchanged state. The first message of a topic is always logged, and
+    /// so is a change of the retain flag
+    #[structopt(long, alias = "dedupe")]
+    dedup: bool,
+
+    /// Payloads larger than this many bytes are handled according to --on-oversize
+    #[structopt(long, env = "MAX_PAYLOAD_BYTES")]
+    max_payload_bytes: Option<usize>,
+
+    /// What to do with payloads above --max-payload-bytes: skip the message, or truncate the
+    /// payload and mark the record with `truncated` and its original size in `orig_len`
+    #[structopt(long, env = "ON_OVERSIZE", default_value = "skip")]
+    on_oversize: Oversize,
+
+    /// Truncate payloads larger than this size, e.g. 64K. Same as --max-payload-bytes with
+    /// --on-oversize truncate
+    #[structopt(
+        long,
+        env = "MAX_PAYLOAD_SIZE",
+        parse(try_from_str = parse_size),
+        conflicts_with = "max-payload-bytes"
+    )]
+    max_payload_size: Option<u64>,
+
+    /// Only log every nth message of each topic, retained messages are always logged
+    #[structopt(
+        long,
+        alias = "sample",
+        env = "SAMPLE_EVERY",
+        conflicts_with = "sample-rate"
+    )]
+    sample_every: Option<u64>,
+
+    /// Whether --sample-every counts the messages of each topic, or of all topics together
+    #[structopt(long, env = "SAMPLE_SCOPE", default_value = "topic")]
+    sample_scope: SampleScope,
+
+    /// Only log each message with this probability between 0.0 and 1.0, retained messages are
+    /// always logged
+    #[structopt(long, env = "SAMPLE_RATE")]
+    sample_rate: Option<f64>,
+
+    /// Seed of --sample-rate, to select the same messages from the same stream
+    #[structopt(long, env = "SEED")]
+    seed: Option<u64>,
+
+    /// Serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9100
+    #[structopt(long, env = "METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// How long to wait for further retained messages with --snapshot, e.g. 2s or 500ms
+    #[structopt(long, env = "QUIET_PERIOD", default_value = "2s")]
+    quiet_period: String,
+
+    /// Append to the output file if it exists instead of failing. The new data is written as a
+    /// separate compressed frame, which zstd, gzip and lz4 tools decode as one continuous file
+    #[structopt(long)]
+    append: bool,
+
+    /// Topic filter to subscribe to, can be given multiple times. Also used when resubscribing
+    /// after a reconnect.
     #[structopt(long, env = "TOPIC", default_value = "#")]
     topic: Vec<String>,
-}
 
-fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
+    /// Topic filter of messages to drop, e.g. '$SYS/#'. Uses MQTT wildcards and supports multiple.
+    #[structopt(long, env = "EXCLUDE")]
+    exclude: Vec<String>,
+
+    /// Only log messages with a topic matching this regular expression, supports multiple
+    #[structopt(long, env = "INCLUDE_REGEX")]
+    include_regex: Vec<String>,
+
+    /// Drop messages with a topic matching this regular expression, e.g. '.*/heartbeat$'. Wins
+    /// over --include-regex and supports multiple
+    #[structopt(long, env = "EXCLUDE_REGEX")]
+    exclude_regex: Vec<String>,
+
+    /// QoS used when subscribing to the topics: 0, 1 or 2
+    #[structopt(long, env = "QOS", default_value = "1", parse(try_from_str = parse_qos))]
+    qos: QoS,
+
+    /// Topic of a last will message, which the broker publishes when the logger disconnects
+    /// without closing the connection, e.g. when it crashes or loses the network
+    #[structopt(long, env = "WILL_TOPIC")]
+    will_topic: Option<String>,
+
+    /// Payload of the last will message, required with --will-topic
+    #[structopt(long, env = "WILL_PAYLOAD")]
+    will_payload: Option<String>,
+
+    /// QoS of the last will message: 0, 1 or 2, 0 if not given
+    #[structopt(long, env = "WILL_QOS", parse(try_from_str = parse_qos))]
+    will_qos: Option<QoS>,
+
+    /// Retain the last will message, so clients subscribing later see the logger is down
+    #[structopt(long)]
+    will_retain: bool,
+
+    /// Keep-alive interval in seconds, at least 5. The broker may enforce its own maximum
+    #[structopt(long, env = "KEEP_ALIVE", default_value = "5")]
+    keep_alive: u16,
+
+    /// Receive with the async MQTT client on a tokio runtime, buffering bursts in memory instead
+    /// of waiting for the log file to be written
+    #[structopt(long)]
+    async_io: bool,
+
+    /// The number of received messages buffered while the log file is written. When it is full,
+    /// receiving waits and the times it did are counted in a warning, drops at the broker are not
+    /// detected. Not used with --async-io. Also sets the capacity of the MQTT
+    /// client's request channel. Raise it for bursty brokers, at the cost of more memory
+    #[structopt(long, env = "CHANNEL_CAPACITY", default_value = "128")]
+    channel_capacity: usize,
+
+    /// Delay in milliseconds before reconnecting to the broker, doubled for every failed attempt
+    #[structopt(long, env = "RECONNECT_BASE_MS", default_value = "500")]
+    reconnect_base_ms: u64,
+
+    /// Maximum delay in milliseconds between reconnection attempts
+    #[structopt(long, env = "RECONNECT_MAX_MS", default_value = "30000")]
+    reconnect_max_ms: u64,
+}
 
-    let server = opt.server;
-    let port = opt.port.unwrap_or(if opt.tls || opt.custom_ca.is_some() {
-        8883
-    } else {
-        1883
-    });
-    let compression_level = opt.compression_level;
-    let duration = if let Some(s) = &opt.duration {
-        Some(parse_duration::parse(&s).expect(&format!(
-            "Unable to parse the --duration argument: '{}'",
-            &s
-        )))
-    } else {
-        None
+/// Parses the command line, adding the options of the --config file which are not given on it.
+///
+/// The dependencies between options are checked in `main`, as they may be met by the file.
+fn parse_options() -> anyhow::Result<Opt> {
+    let matches = Opt::clap().get_matches();
+    let path = match matches.value_of_os("config") {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(Opt::from_clap(&matches)),
     };
-    let forever = opt.forever;
-    let mut output = if !forever {
-        opt.output.clone()
-    } else {
-        if duration.unwrap() < Duration::from_secs(1) {
-            return Err(anyhow!(
-                "The duration needs to be more than 1 second when using --forever"
-            ));
+
+    let config: BTreeMap<String, toml::Value> = toml::from_str(
+        &fs::read_to_string(&path)
+            .with_context(|| format!("Could not read config file '{}'", path.display()))?,
+    )
+    .with_context(|| format!("Invalid config file '{}'", path.display()))?;
+
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let mut config_args = Vec::new();
+    for (key, value) in config {
+        let name = key.replace('_', "-");
+        if matches.occurrences_of(&name) > 0 {
+            continue;
         }
 
-        let mut output = opt.output.clone();
-
-        let utc: DateTime<Utc> = Utc::now();
-        let filename = output
-            .file_stem()
-            .expect("Empty filename?")
-            .to_str()
-            .expect("Non-unicode path?");
-        let now_time = format!(
-            "{}-{}",
-            filename,
-            utc.to_rfc3339_opts(SecondsFormat::Secs, false)
-        );
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(value) if takes_value(&name) => value.to_string(),
+                // Flags are set by being given at all
+                toml::Value::Boolean(true) => {
+                    config_args.push(format!("--{}", name).into());
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                _ => {
+                    return Err(anyhow!(
+                        "Unsupported value of '{}' in config file '{}'",
+                        key,
+                        path.display()
+                    ))
+                }
+            };
 
-        output.set_file_name(now_time);
+            // The output is positional, and goes first so no option takes it as its value
+            if name == "output" {
+                config_args.insert(0, value.into());
+            } else {
+                config_args.push(format!("--{}={}", name, value).into());
+            }
+        }
+    }
+    args.splice(1..1, config_args);
 
-        println!("output: {:?}", output);
+    Ok(Opt::from_clap(&Opt::clap().get_matches_from(args)))
+}
 
-        output
-    };
+/// Reads the PEM certificates of a custom CA, none for the paths `null`, `None` and `none`.
+fn load_custom_ca(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    if matches!(path.to_str(), Some("null" | "None" | "none")) {
+        return Ok(None);
+    }
 
-    output.set_extension("json.zst");
+    let ca =
+        fs::read(path).with_context(|| format!("Could not read CA file '{}'", path.display()))?;
+
+    // Validate here, rumqttc only parses the CA once it tries to connect
+    match rumqttc::certs(&mut Cursor::new(&ca)) {
+        Ok(certs) if !certs.is_empty() => Ok(Some(ca)),
+        _ => Err(anyhow!(
+            "No valid PEM certificates found in CA file '{}'",
+            path.display()
+        )),
+    }
+}
+
+/// Sets the flags which are also read from the environment, as clap would make them take a value.
+fn env_flags(opt: &mut Opt) -> anyhow::Result<()> {
+    opt.tls |= env_flag("TLS")?;
+    opt.forever |= env_flag("FOREVER")?;
+    Ok(())
+}
+
+/// Reads a flag from the environment variable `name`, unset or empty meaning false.
+fn env_flag(name: &str) -> anyhow::Result<bool> {
+    match std::env::var(name) {
+        Ok(value) if value.is_empty() => Ok(false),
+        Ok(value) => value.parse().map_err(|_| {
+            anyhow!(
+                "Invalid value '{}' of the environment variable {}, expected true or false",
+                value,
+                name
+            )
+        }),
+        Err(std::env::VarError::NotPresent) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Invalid environment variable {}", name)),
+    }
+}
+
+/// Whether the option `name` takes a value, like `--header false`, rather than being a flag.
+fn takes_value(name: &str) -> bool {
+    // A flag leaves the value to the positional output
+    Opt::clap()
+        .get_matches_from_safe(["mqtt-logger", &format!("--{}", name), "true"])
+        .is_ok_and(|matches| matches.value_of(name).is_some())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut opt = parse_options()?;
+    env_flags(&mut opt)?;
+
+    if opt.output.as_deref() == Some(Path::new("-")) {
+        if opt.print {
+            return Err(anyhow!("--print can not be used with the output '-'"));
+        }
+        opt.output = None;
+        opt.stdout = true;
+    }
+    // Colors are only for people watching, not for pipes or files
+    let color = opt.tail
+        && io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    if opt.tail {
+        opt.print = true;
+    }
+    if opt.output.is_none() && !opt.print && !opt.stdout {
+        return Err(anyhow!(
+            "An output log file is required, unless printing with --print or --stdout"
+        ));
+    }
+    if opt.output.is_some() && opt.stdout {
+        return Err(anyhow!(
+            "--stdout writes the log to stdout, no output file is used"
+        ));
+    }
+    if opt.client_cert.is_some() != opt.client_key.is_some() {
+        return Err(anyhow!(
+            "--client-cert and --client-key are required together"
+        ));
+    }
+    if opt.forever && opt.duration.is_none() {
+        return Err(anyhow!("--forever requires --duration"));
+    }
+    if opt.seed.is_some() && opt.sample_rate.is_none() {
+        return Err(anyhow!("--seed requires --sample-rate"));
+    }
+    if opt.persistent_session && opt.client_id.is_none() {
+        return Err(anyhow!(
+            "--persistent-session requires a fixed --client-id, the broker finds the session by it"
+        ));
+    }
+    let will_options = opt.will_topic.is_some()
+        || opt.will_payload.is_some()
+        || opt.will_qos.is_some()
+        || opt.will_retain;
+    if will_options && (opt.will_topic.is_none() || opt.will_payload.is_none()) {
+        return Err(anyhow!(
+            "The last will options require --will-topic and --will-payload"
+        ));
+    }
+
+    let password = match &opt.password_file {
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .with_context(|| format!("Could not read password file '{}'", path.display()))?
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_string(),
+        ),
+        None => opt.password.clone(),
+    };
+    let credentials = match (&opt.username, password) {
+        (Some(username), Some(password)) => Some((username.clone(), password)),
+        (None, None) => None,
+        _ => {
+            return Err(anyhow!(
+            "Both --username and --password (or --password-file) are required for authentication"
+        ))
+        }
+    };
+    if opt.ws && (opt.wss || opt.tls || opt.custom_ca.is_some() || opt.client_cert.is_some()) {
+        return Err(anyhow!(
+            "--ws is a plain WebSocket and can not be combined with TLS, use --wss instead"
+        ));
+    }
+    let codec = opt.codec;
+    if opt.compression_level.is_some() && !codec.has_level() {
+        eprintln!("Warning: --compression-level has no effect with the selected codec");
+    }
+    let compression_level = opt.compression_level.unwrap_or(9);
+    if opt.compression_threads > 1 && codec != Codec::Zstd {
+        eprintln!("Warning: --compression-threads has no effect with the selected codec");
+    }
+    let duration = match &opt.duration {
+        Some(s) => Some(
+            parse_duration::parse(s)
+                .map_err(|e| anyhow!("Unable to parse the --duration argument '{}': {}", s, e))?,
+        ),
+        None => None,
+    };
+    let forever = opt.forever;
+    // --forever is rotation with the interval given by --duration
+    let rotate_interval = match &opt.rotate_interval {
+        Some(s) => Some(parse_duration::parse(s).map_err(|e| {
+            anyhow!(
+                "Unable to parse the --rotate-interval argument '{}': {}",
+                s,
+                e
+            )
+        })?),
+        None if forever => duration,
+        None => None,
+    };
+    let frame_interval = match &opt.frame_interval {
+        Some(s) => Some(parse_duration::parse(s).map_err(|e| {
+            anyhow!(
+                "Unable to parse the --frame-interval argument '{}': {}",
+                s,
+                e
+            )
+        })?),
+        None if opt.index => Some(Duration::from_secs(60)),
+        None => None,
+    };
+    let flush_interval = match &opt.flush_interval {
+        Some(s) => Some(parse_duration::parse(s).map_err(|e| {
+            anyhow!(
+                "Unable to parse the --flush-interval argument '{}': {}",
+                s,
+                e
+            )
+        })?),
+        None => None,
+    };
+    // The spinner only works on a terminal, elsewhere plain lines are printed
+    let progress = if opt.no_progress {
+        Progress::Off
+    } else if io::stderr().is_terminal() {
+        Progress::Spinner
+    } else {
+        let interval = &opt.progress_interval;
+        Progress::Lines(parse_duration::parse(interval).map_err(|e| {
+            anyhow!(
+                "Unable to parse the --progress-interval argument '{}': {}",
+                interval,
+                e
+            )
+        })?)
+    };
+    let quiet_period = &opt.quiet_period;
+    let snapshot_quiet = if opt.snapshot {
+        Some(parse_duration::parse(quiet_period).map_err(|e| {
+            anyhow!(
+                "Unable to parse the --quiet-period argument '{}': {}",
+                quiet_period,
+                e
+            )
+        })?)
+    } else {
+        None
+    };
 
     match opt.verbosity {
         0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
@@ -134,244 +677,325 @@ fn main() -> anyhow::Result<()> {
     }
     .init()?;
 
-    // Ctrl+C handler
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    let ports: Vec<Option<u16>> = match opt.port.len() {
+        0 => vec![None; opt.server.len()],
+        1 => vec![Some(opt.port[0]); opt.server.len()],
+        n if n == opt.server.len() => opt.port.iter().copied().map(Some).collect(),
+        _ => {
+            return Err(anyhow!(
+                "Give one --port for all servers, or one per --server"
+            ))
+        }
+    };
+
+    let mut builder = Logger::builder()
+        .server(opt.server[0].clone())
+        .topics(opt.topic.clone())
+        .qos(opt.qos)
+        .exclude(opt.exclude.clone())
+        .include_regex(opt.include_regex.clone())
+        .exclude_regex(opt.exclude_regex.clone())
+        .keep_alive(Duration::from_secs(opt.keep_alive.into()))
+        .text_payloads(opt.text_payloads)
+        .payload_encoding(opt.payload_encoding)
+        .time_format(opt.time_format)
+        .time_zone(opt.timezone)
+        .async_io(opt.async_io)
+        .channel_capacity(opt.channel_capacity)
+        .reconnect_backoff(
+            Duration::from_millis(opt.reconnect_base_ms),
+            Duration::from_millis(opt.reconnect_max_ms),
+        )
+        .progress(progress);
+
+    if let Some(port) = ports[0] {
+        builder = builder.port(port);
+    }
 
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-        info!("Shutting down and saving log file...");
-    })
-    .expect("Error setting Ctrl-C handler");
+    for (server, port) in opt.server.iter().zip(&ports).skip(1) {
+        builder = builder.fallback_server(server.clone(), *port);
+    }
 
-    let log_file = BufWriter::with_capacity(
-        128 * 1024, // 128 kB cache
-        fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&output)?,
-    );
-    let mut log_file = zstd::Encoder::new(log_file, compression_level)?.auto_finish();
+    if let Some(client_id) = &opt.client_id {
+        builder = builder.client_id(client_id.clone());
+    }
+    builder = builder.clean_session(opt.clean_session && !opt.persistent_session);
 
-    // -------------------------- MQTT Start ---------------------------
+    if let Some((username, password)) = credentials {
+        builder = builder.credentials(username, password);
+    }
 
-    let nanos = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or(Duration::new(0, 1))
-        .subsec_nanos();
-    let mut mqtt_options = MqttOptions::new(&format!("mqtt-logger-sub{}", nanos), &server, port);
+    if let (Some(topic), Some(payload)) = (&opt.will_topic, &opt.will_payload) {
+        builder = builder.last_will(
+            topic.clone(),
+            payload.clone(),
+            opt.will_qos.unwrap_or(QoS::AtMostOnce),
+            opt.will_retain,
+        );
+    }
 
-    // Check for custom CA file
-    let custom_ca = if let Some(custom_ca_path) = &opt.custom_ca {
-        use std::io::prelude::*;
+    if opt.tls || opt.wss {
+        builder = builder.tls(true);
+    }
 
-        let ca_str = custom_ca_path.to_str();
+    if opt.ws || opt.wss {
+        builder = builder.websocket(opt.ws_path.clone());
+    }
 
-        if !(ca_str == Some("null") || ca_str == Some("None") || ca_str == Some("none")) {
-            let mut vec = Vec::new();
+    // Check for custom CA file
+    let mut custom_ca_loaded = false;
+    if let Some(custom_ca_path) = &opt.custom_ca {
+        if let Some(ca) = load_custom_ca(custom_ca_path)? {
+            builder = builder.custom_ca(ca);
+            custom_ca_loaded = true;
+        }
+    }
 
-            fs::File::open(custom_ca_path)
-                .expect("Could not open CA file")
-                .read_to_end(&mut vec)
-                .expect("Could not read specified CA file");
+    // Check for client certificate and key
+    if let (Some(cert_path), Some(key_path)) = (&opt.client_cert, &opt.client_key) {
+        let (cert, key) = mqtt_logger::load_client_auth(cert_path, key_path)?;
+        builder = builder.client_auth(cert, key);
+    }
 
-            Some(vec)
-        } else {
-            None
+    if !forever {
+        if let Some(duration) = duration {
+            builder = builder.stop_after(duration);
         }
-    } else {
-        None
-    };
+    }
 
-    // Encrypted MQTT?
-    if opt.tls || custom_ca.is_some() {
-        let transport = if let Some(custom_ca) = &custom_ca {
-            Transport::Tls(TlsConfiguration::Simple {
-                ca: custom_ca.clone(),
-                alpn: None,
-                client_auth: None,
-            })
-        } else {
-            let mut client_config = ClientConfig::new();
-            // Use rustls-native-certs to load root certificates from the operating system.
-            client_config.root_store = rustls_native_certs::load_native_certs()
-                .expect("Failed to load platform certificates.");
-
-            Transport::tls_with_config(client_config.into())
-        };
+    if let Some(max_messages) = opt.max_messages {
+        builder = builder.max_messages(max_messages);
+    }
 
-        mqtt_options.set_transport(transport);
+    if let Some(quiet_period) = snapshot_quiet {
+        builder = builder.snapshot(quiet_period);
     }
 
-    mqtt_options.set_keep_alive(Duration::from_secs(5));
-    let (mut mqtt_client, mut notifications) = Client::new(mqtt_options, 10);
+    builder = builder
+        .skip_retained(opt.skip_retained)
+        .skip_empty(opt.skip_empty)
+        .sequence(opt.sequence)
+        .color(color)
+        .protocol_fields(opt.protocol_fields)
+        .dedup(opt.dedup);
 
-    if opt.topic.is_empty() {
-        return Err(anyhow!("No topics supplied"));
+    if let Some(n) = opt.sample_every {
+        builder = builder.sampling(Sampling::Every(n, opt.sample_scope));
     }
 
-    for topic in &opt.topic {
-        mqtt_client.subscribe(topic, QoS::AtLeastOnce)?;
+    if let Some(rate) = opt.sample_rate {
+        builder = builder.sampling(Sampling::Rate {
+            rate,
+            seed: opt.seed,
+        });
     }
 
-    // -------------------------- MQTT END ---------------------------
-    println!(
-        "Starting logging with ZSTD compression (level {}) into '{}' on address '{}:{}'",
-        compression_level,
-        output.to_str().unwrap(),
-        server,
-        port
-    );
+    if let Some(max_reconnects) = opt.max_reconnects {
+        builder = builder.max_reconnects(max_reconnects);
+    }
 
-    for topic in &opt.topic {
-        println!("    - Subscribing to topic '{}'", topic);
+    if let Some(interval) = flush_interval {
+        builder = builder.flush_interval(interval);
     }
 
-    if opt.tls || custom_ca.is_some() {
-        let certs: String = if custom_ca.is_some() {
-            format!(
-                "custom CA loaded from '{}'",
-                opt.custom_ca.unwrap().to_str().unwrap()
-            )
-        } else {
-            "using native certs".into()
-        };
+    if let Some(addr) = opt.metrics_addr {
+        builder = builder.metrics_addr(addr);
+    }
+
+    if let Some(max_bytes) = opt.max_payload_bytes {
+        builder = builder.max_payload(max_bytes, opt.on_oversize);
+    }
+
+    if let Some(max_size) = opt.max_payload_size {
+        builder = builder.max_payload(max_size as usize, Oversize::Truncate);
+    }
 
-        println!("    - using TLS ({})", certs,);
+    // Stdout is line buffered, so each record reaches the reader right away
+    if opt.stdout {
+        builder = builder.output(Output::Writer(
+            Arc::new(Mutex::new(io::stdout())),
+            opt.format,
+        ));
     }
 
-    if let Some(dur) = &duration {
-        if forever {
-            println!(
-                "    - Running forever, saving logfiles every {:?} ({})",
-                dur,
-                opt.duration.unwrap()
-            );
-        } else {
-            println!("    - Stopping after {:?} ({})", dur, opt.duration.unwrap());
+    if let Some(output) = &opt.output {
+        if !opt.print {
+            builder = builder.output(Output::Files(LogFiles {
+                path: output.clone(),
+                format: opt.format,
+                codec,
+                compression_level,
+                compression_threads: opt.compression_threads,
+                append: opt.append,
+                rotate_interval,
+                // Only --rotate-interval is aligned to the clock, --forever rotates relative to
+                // the start
+                align_rotation: opt.rotate_interval.is_some(),
+                max_size: opt.max_size,
+                split_by_topic: opt.split_by_topic,
+                frame_interval,
+                max_open_files: opt.max_open_files,
+                fsync: opt.fsync,
+                buffer_size: opt.buffer_size as usize,
+                header: opt.header,
+            }));
         }
     }
 
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(80);
-    pb.set_style(
-        ProgressStyle::default_spinner()
-            .tick_strings(&[
-                "[    ]", "[=   ]", "[==  ]", "[=== ]", "[ ===]", "[  ==]", "[   =]", "[    ]",
-                "[   =]", "[  ==]", "[ ===]", "[====]", "[=== ]", "[==  ]", "[=   ]",
-            ])
-            .template("{spinner} {msg}"),
-    );
-    pb.set_message("Logging... No messages recorded yet.");
-
-    let mut count: u64 = 0;
-    let mut bytes_written = 0.;
-    let mut connected = true;
-    let time_start = SystemTime::now();
-    let mut duration_check = time_start;
-
-    for notification in notifications.iter() {
-        if !running.load(Ordering::SeqCst) {
-            pb.finish();
-            break;
+    let logger = builder.build()?;
+
+    // Keep stdout clean for piping when printing messages
+    if let Some(output) = logger.output_path() {
+        if rotate_interval.is_some() {
+            println!("output: {:?}", output);
         }
 
-        if let Some(dur) = duration {
-            if SystemTime::now().duration_since(duration_check)? > dur {
-                if forever {
-                    duration_check = SystemTime::now();
-                    let mut output = opt.output.clone();
-
-                    let utc: DateTime<Utc> = Utc::now();
-                    let filename = output
-                        .file_stem()
-                        .expect("Empty filename?")
-                        .to_str()
-                        .expect("Non-unicode path?");
-                    let now_time = format!(
-                        "{}-{}",
-                        filename,
-                        utc.to_rfc3339_opts(SecondsFormat::Secs, false)
-                    );
-
-                    output.set_file_name(now_time);
-                    output.set_extension("json.zst");
-
-                    let lf = BufWriter::with_capacity(
-                        128 * 1024, // 128 kB cache
-                        fs::OpenOptions::new()
-                            .write(true)
-                            .create_new(true)
-                            .open(&output)?,
-                    );
-                    log_file = zstd::Encoder::new(lf, compression_level)?.auto_finish();
-                } else {
-                    running.store(false, Ordering::SeqCst);
-                }
-            }
+        println!(
+            "Starting logging with {} into '{}' on address '{}'",
+            match codec {
+                Codec::Zstd if opt.compression_threads > 1 => format!(
+                    "ZSTD compression (level {}, {} threads)",
+                    compression_level, opt.compression_threads
+                ),
+                Codec::Zstd => format!("ZSTD compression (level {})", compression_level),
+                Codec::Gzip => format!("gzip compression (level {})", compression_level),
+                Codec::Lz4 => "LZ4 compression".to_string(),
+                Codec::None => "no compression".to_string(),
+            },
+            output.to_str().unwrap(),
+            logger.address()
+        );
+
+        if let Some(config) = &opt.config {
+            println!("    - Using options from '{}'", config.display());
         }
 
-        match notification {
-            Ok(Event::Incoming(Incoming::Publish(msg))) => {
-                let msg = MqttMessage {
-                    time: SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)?
-                        .as_secs_f64(),
-                    qos: msg.qos as u8,
-                    retain: msg.retain,
-                    topic: msg.topic,
-                    msg_b64: base64::encode(&*msg.payload),
-                };
-
-                if let Ok(serialized) = serde_json::to_string(&msg) {
-                    count += 1;
-                    bytes_written += serialized.len() as f64 + 2.; // 2 = newline
-
-                    pb.set_message(Cow::Owned(format!(
-                        "Logging... {} messages recorded, uncompressed data size: {:.2} MB.",
-                        count,
-                        bytes_written / 1024. / 1024.,
-                    )));
-                    writeln!(log_file, "{}", serialized)?
-                }
-            }
-            Ok(Event::Incoming(Incoming::Disconnect)) => {
-                debug!("Disconnected, trying to reconnect...");
-                connected = false;
-            }
-            Ok(Event::Outgoing(Outgoing::PingReq)) => {
-                if !connected {
-                    debug!("Trying to resubscribe...");
+        for address in logger.fallback_addresses() {
+            println!("    - Failing over to '{}'", address);
+        }
 
-                    for topic in &opt.topic {
-                        mqtt_client.subscribe(topic, QoS::AtLeastOnce)?;
-                    }
+        println!("    - Using client id '{}'", logger.client_id());
 
-                    connected = true;
-                }
+        for topic in logger.topics() {
+            println!("    - Subscribing to topic '{}'", topic);
+        }
+
+        if let Some(topic) = &opt.will_topic {
+            println!("    - Last will on topic '{}'", topic);
+        }
+
+        if logger.tls() {
+            let certs: String = if custom_ca_loaded {
+                format!(
+                    "custom CA loaded from '{}'",
+                    opt.custom_ca.as_ref().unwrap().to_str().unwrap()
+                )
+            } else {
+                "using native certs".into()
+            };
+
+            println!("    - using TLS ({})", certs,);
+        }
+
+        if let Some(dur) = &duration {
+            if forever {
+                println!(
+                    "    - Running forever, saving logfiles every {:?} ({})",
+                    dur,
+                    opt.duration.as_ref().unwrap()
+                );
+            } else {
+                println!(
+                    "    - Stopping after {:?} ({})",
+                    dur,
+                    opt.duration.as_ref().unwrap()
+                );
             }
-            Ok(val) => trace!("Unhandled Ok(...) notification: {:?}", val),
-            Err(val) => match val {
-                ConnectionError::MqttState(e) => {
-                    debug!("MQTT error, will try to reconnect when possible: {:?}", e);
-                    connected = false;
-                }
-                ConnectionError::Network(e) => {
-                    debug!(
-                        "Network error, will try to reconnect when possible: {:?}",
-                        e
-                    );
-                    connected = false;
-                }
-                _ => {
-                    trace!("Unhandled Err(...) notification: {:?}", val);
-                    connected = false;
-                }
-            },
         }
     }
 
-    log_file.flush()?;
+    // Ctrl+C handler, also used for SIGTERM and SIGHUP so services stop with a complete log file
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let s = shutdown.clone();
 
-    Ok(())
+    ctrlc::set_handler(move || {
+        s.store(true, Ordering::SeqCst);
+        info!("Shutting down and saving log file...");
+    })
+    .expect("Error setting the shutdown signal handler");
+
+    logger.run(shutdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Opt {
+        Opt::from_iter_safe(std::iter::once("mqtt-logger").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn flags_take_no_value() {
+        let opt = parse(&["--print"]);
+        assert!(opt.print);
+        assert_eq!(opt.output, None);
+
+        let opt = parse(&["--fsync", "out.json"]);
+        assert!(opt.fsync);
+        assert_eq!(opt.output, Some(PathBuf::from("out.json")));
+
+        assert!(!parse(&["out.json"]).print);
+
+        let opt = parse(&["--append", "out.json"]);
+        assert!(opt.append);
+        assert_eq!(opt.output, Some(PathBuf::from("out.json")));
+    }
+
+    #[test]
+    fn tls_from_flag_or_environment() {
+        assert!(parse(&["--tls", "out.json"]).tls);
+        assert!(parse(&["out.json", "--tls"]).tls);
+
+        let mut opt = parse(&["out.json"]);
+        assert!(!opt.tls);
+        std::env::set_var("TLS", "true");
+        let set = env_flags(&mut opt);
+        std::env::set_var("TLS", "yes");
+        let invalid = env_flag("TLS");
+        std::env::remove_var("TLS");
+        set.unwrap();
+        assert!(opt.tls);
+        assert!(!opt.forever);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn custom_ca_files() {
+        let certs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs");
+
+        assert!(load_custom_ca(&certs.join("ca.pem")).unwrap().is_some());
+        assert!(load_custom_ca(Path::new("none")).unwrap().is_none());
+
+        let missing = load_custom_ca(&certs.join("missing.pem")).unwrap_err();
+        assert!(missing.to_string().starts_with("Could not read CA file"));
+        let not_pem = load_custom_ca(&certs.join("localhost.key")).unwrap_err();
+        assert!(not_pem.to_string().starts_with("No valid PEM certificates"));
+        assert!(load_custom_ca(&certs.join("../../Cargo.toml")).is_err());
+        assert!(load_custom_ca(&certs).is_err());
+    }
+
+    #[test]
+    fn boolean_options_take_a_value() {
+        assert!(!parse(&["--header", "false", "out.json"]).header);
+        assert!(parse(&["out.json"]).header);
+    }
+
+    #[test]
+    fn config_values_of_flags_and_options() {
+        assert!(takes_value("header"));
+        assert!(takes_value("clean-session"));
+        assert!(!takes_value("print"));
+        assert!(!takes_value("dedup"));
+    }
 }
@@ -0,0 +1,183 @@
+use anyhow::Context;
+use log::*;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Counters of a running logger, in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    reconnects: AtomicU64,
+    queued: AtomicU64,
+    topics: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn message(&self, topic: &str) {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        *self
+            .topics
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A notification was queued for the logging loop.
+    pub fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A queued notification was taken by the logging loop.
+    pub fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "mqtt_logger_messages_total",
+            "counter",
+            "Messages recorded.",
+            self.messages.load(Ordering::SeqCst),
+        );
+        metric(
+            "mqtt_logger_bytes_written_total",
+            "counter",
+            "Uncompressed bytes written to the log.",
+            self.bytes.load(Ordering::SeqCst),
+        );
+        metric(
+            "mqtt_logger_reconnects_total",
+            "counter",
+            "Reconnections to the broker.",
+            self.reconnects.load(Ordering::SeqCst),
+        );
+        metric(
+            "mqtt_logger_queued_notifications",
+            "gauge",
+            "Notifications received from the broker and waiting to be logged.",
+            self.queued.load(Ordering::SeqCst),
+        );
+
+        out += "# HELP mqtt_logger_topic_messages_total Messages recorded per topic.\n";
+        out += "# TYPE mqtt_logger_topic_messages_total counter\n";
+        let topics = self.topics.lock().unwrap();
+        let mut sorted: Vec<_> = topics.iter().collect();
+        sorted.sort();
+        for (topic, count) in sorted {
+            let _ = writeln!(
+                out,
+                "mqtt_logger_topic_messages_total{{topic=\"{}\"}} {}",
+                escape_label(topic),
+                count
+            );
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics over HTTP until dropped.
+pub struct MetricsServer {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Listens on `addr`, every request is answered with the metrics.
+    pub fn start(addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Unable to serve metrics on '{}'", addr))?;
+        // Polled, so the server notices when it is stopped
+        listener.set_nonblocking(true)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if let Err(e) = respond(stream, &metrics) {
+                            debug!("Unable to serve metrics to {}: {}", peer, e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    Err(e) => {
+                        debug!("Unable to accept a metrics connection: {}", e);
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        });
+
+        Ok(MetricsServer {
+            stop,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    // The request is not parsed, but read up to the end of its headers before answering
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let body = metrics.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
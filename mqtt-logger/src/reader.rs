@@ -0,0 +1,135 @@
+use crate::writer::MSGPACK_MAGIC;
+use crate::{LogHeader, MqttMessage, LOG_FORMAT};
+use log::*;
+use std::fmt;
+use std::io::{self, BufRead, ErrorKind, Lines, Read};
+
+/// Why a record could not be read.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The record is skipped, reading continues with the next one
+    Corrupt(String),
+    /// Nothing more can be read, e.g. the log file is truncated
+    Io(io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Corrupt(reason) => write!(f, "{}", reason),
+            ReadError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Reads the messages of a decompressed log, in JSON lines or MessagePack.
+pub enum LogReader<R: BufRead> {
+    /// The lines, and the number of the last line read
+    Json(Lines<R>, u64),
+    MsgPack(R),
+}
+
+impl<R: BufRead> LogReader<R> {
+    /// Detects the format from the start of the log, JSON lines start with `{` and MessagePack
+    /// with [`MSGPACK_MAGIC`].
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        if inner.fill_buf()?.first() != Some(&MSGPACK_MAGIC[0]) {
+            return Ok(LogReader::Json(inner.lines(), 0));
+        }
+
+        let mut magic = vec![0; MSGPACK_MAGIC.len()];
+        inner.read_exact(&mut magic)?;
+        if magic != MSGPACK_MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Unknown log format, expected JSON lines or MessagePack",
+            ));
+        }
+
+        Ok(LogReader::MsgPack(inner))
+    }
+
+    /// The line of the last record read from a JSON log, counting header records.
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            LogReader::Json(_, line) => Some(*line),
+            LogReader::MsgPack(_) => None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LogReader<R> {
+    type Item = Result<MqttMessage, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            LogReader::Json(lines, number) => loop {
+                *number += 1;
+                let line = match lines.next()? {
+                    Ok(line) => line,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        return Some(Err(ReadError::Corrupt(
+                            "line is not valid UTF-8".to_string(),
+                        )));
+                    }
+                    Err(e) => return Some(Err(ReadError::Io(e))),
+                };
+
+                trace!("{:?}", &line);
+
+                let e = match serde_json::from_str(&line) {
+                    Ok(msg) => return Some(Ok(msg)),
+                    Err(e) => e,
+                };
+
+                // Each file of a capture starts with a header, also within concatenated logs
+                if let Ok(header) = serde_json::from_str::<LogHeader>(&line) {
+                    if header.format != LOG_FORMAT {
+                        return Some(Err(ReadError::Corrupt(format!(
+                            "Unsupported log format '{}' of mqtt-logger {}, expected '{}'",
+                            header.format, header.version, LOG_FORMAT
+                        ))));
+                    }
+
+                    debug!(
+                        "Log of '{}' started at {} by mqtt-logger {}",
+                        header.server, header.started, header.version
+                    );
+                    continue;
+                }
+
+                return Some(Err(ReadError::Corrupt(format!(
+                    "Serde error with line '{}', error: {}",
+                    line, e
+                ))));
+            },
+            LogReader::MsgPack(inner) => {
+                match inner.fill_buf() {
+                    Ok([]) => return None,
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(ReadError::Io(e))),
+                }
+
+                let mut len = [0; 4];
+                if let Err(e) = inner.read_exact(&mut len) {
+                    return Some(Err(ReadError::Io(e)));
+                }
+                let len = u32::from_le_bytes(len) as u64;
+
+                // Read through `take`, so a corrupted length does not allocate gigabytes up front
+                let mut record = Vec::new();
+                if let Err(e) = inner.by_ref().take(len).read_to_end(&mut record) {
+                    return Some(Err(ReadError::Io(e)));
+                }
+                if (record.len() as u64) < len {
+                    return Some(Err(ReadError::Io(ErrorKind::UnexpectedEof.into())));
+                }
+
+                Some(
+                    rmp_serde::from_slice(&record)
+                        .map_err(|e| ReadError::Corrupt(format!("MessagePack error: {}", e))),
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{LogHeader, MqttMessage, Payload};
+use anyhow::anyhow;
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Record format of the log file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    /// Length prefixed MessagePack records with raw payloads, after [`MSGPACK_MAGIC`]
+    MsgPack,
+}
+
+/// Starts MessagePack logs, so readers can tell them from JSON lines.
+pub const MSGPACK_MAGIC: &[u8] = b"mqtt-log msgpack\n";
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "msgpack" => Ok(Format::MsgPack),
+            _ => Err(anyhow!(
+                "Unknown format '{}', expected json, csv or msgpack",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    /// The extension of log files in this format, before the codec extension.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::MsgPack => "msgpack",
+        }
+    }
+
+    /// Creates a writer of this format on top of `inner`, headers are skipped when appending.
+    pub fn writer<W: Write + 'static>(
+        &self,
+        inner: W,
+        append: bool,
+    ) -> anyhow::Result<Box<dyn MessageWriter>> {
+        Ok(match self {
+            Format::Json => Box::new(JsonLinesWriter::new(inner)),
+            Format::Csv => Box::new(CsvWriter::new(inner, !append)?),
+            Format::MsgPack => Box::new(MsgPackWriter::new(inner, !append)?),
+        })
+    }
+}
+
+/// A message which could not be serialized, nothing of it was written to the log file.
+#[derive(Debug)]
+pub struct SerializeError(pub String);
+
+impl std::fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+/// Serializes messages into a log file.
+///
+/// Dropping a writer flushes it and finishes the compressed stream, so the log file stays
+/// decodable when logging ends early with an error or a panic.
+pub trait MessageWriter {
+    /// Writes a message, returning the number of bytes written. A message which can not be
+    /// serialized fails with a [`SerializeError`], the log file can still be written to.
+    fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize>;
+
+    /// Writes the header record of a new file, formats without one ignore it.
+    fn write_header(&mut self, _header: &LogHeader) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+/// One JSON object per line.
+pub struct JsonLinesWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(inner: W) -> Self {
+        JsonLinesWriter { inner }
+    }
+}
+
+impl<W: Write> MessageWriter for JsonLinesWriter<W> {
+    fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize> {
+        let serialized = serde_json::to_string(msg).map_err(|e| SerializeError(e.to_string()))?;
+        writeln!(self.inner, "{}", serialized)?;
+
+        Ok(serialized.len() + 1)
+    }
+
+    fn write_header(&mut self, header: &LogHeader) -> anyhow::Result<usize> {
+        let serialized = serde_json::to_string(header)?;
+        writeln!(self.inner, "{}", serialized)?;
+
+        Ok(serialized.len() + 1)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+/// CSV with a header row, the topic and text payload are always quoted.
+pub struct CsvWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(mut inner: W, header: bool) -> anyhow::Result<Self> {
+        if header {
+            writeln!(inner, "time,qos,retain,topic,msg_b64,msg_hex,msg")?;
+        }
+
+        Ok(CsvWriter { inner })
+    }
+}
+
+impl<W: Write> MessageWriter for CsvWriter<W> {
+    fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize> {
+        // Only the column of the payload encoding is filled in
+        let payload = match &msg.payload {
+            Payload::Base64(b64) => format!("{},,", b64),
+            Payload::Bytes(bytes) => format!("{},,", base64::encode(bytes)),
+            Payload::Hex(hex) => format!(",{},", hex),
+            Payload::Text(text) => format!(",,{}", quote(text)),
+        };
+        let line = format!(
+            "{},{},{},{},{}",
+            msg.time,
+            msg.qos,
+            msg.retain,
+            quote(&msg.topic),
+            payload,
+        );
+        writeln!(self.inner, "{}", line)?;
+
+        Ok(line.len() + 1)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+/// MessagePack records, each prefixed with its length as a little endian `u32`.
+pub struct MsgPackWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> MsgPackWriter<W> {
+    pub fn new(mut inner: W, header: bool) -> anyhow::Result<Self> {
+        if header {
+            inner.write_all(MSGPACK_MAGIC)?;
+        }
+
+        Ok(MsgPackWriter { inner })
+    }
+}
+
+impl<W: Write> MessageWriter for MsgPackWriter<W> {
+    fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize> {
+        // Named fields, the flattened time and payload can not be written as arrays
+        let record = rmp_serde::to_vec_named(msg).map_err(|e| SerializeError(e.to_string()))?;
+        self.inner.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&record)?;
+
+        Ok(record.len() + 4)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+/// Opens the writer of a topic prefix, given as a relative path without extension. The flag is
+/// set when the prefix had a writer before, which was closed to stay within the open file limit.
+pub type OpenWriter = Box<dyn FnMut(&Path, bool) -> anyhow::Result<Box<dyn MessageWriter>>>;
+
+/// Routes messages into one writer per prefix of the first `level` topic segments.
+///
+/// At most `max_open` writers are kept open, the least recently used one is closed to open
+/// another, and reopened for appending when its prefix is seen again.
+pub struct SplitWriter {
+    level: usize,
+    max_open: usize,
+    open: OpenWriter,
+    /// The open writers, with the message count when they were last written to
+    writers: HashMap<String, (Box<dyn MessageWriter>, u64)>,
+    closed: HashSet<String>,
+    messages: u64,
+}
+
+impl SplitWriter {
+    pub fn new(level: usize, max_open: usize, open: OpenWriter) -> Self {
+        SplitWriter {
+            level,
+            max_open,
+            open,
+            writers: HashMap::new(),
+            closed: HashSet::new(),
+            messages: 0,
+        }
+    }
+}
+
+impl MessageWriter for SplitWriter {
+    fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize> {
+        let prefix = msg
+            .topic
+            .split('/')
+            .take(self.level)
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if !self.writers.contains_key(&prefix) {
+            if self.writers.len() >= self.max_open {
+                let least_recent = self
+                    .writers
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(prefix, _)| prefix.clone());
+
+                if let Some(least_recent) = least_recent {
+                    debug!("Closing the log file of topic prefix '{}'", least_recent);
+                    // Dropping the writer finishes its file
+                    self.writers.remove(&least_recent);
+                    self.closed.insert(least_recent);
+                }
+            }
+
+            let reopen = self.closed.remove(&prefix);
+            let writer = (self.open)(&prefix_path(&prefix), reopen)?;
+            self.writers.insert(prefix.clone(), (writer, 0));
+        }
+
+        self.messages += 1;
+        let (writer, last_used) = self
+            .writers
+            .get_mut(&prefix)
+            .expect("Writer was just opened");
+        *last_used = self.messages;
+
+        writer.write_message(msg)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for (writer, _) in self.writers.values_mut() {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One directory per topic segment, characters which are not valid in file names are escaped
+/// as `%XX` and an empty segment becomes `%`.
+fn prefix_path(prefix: &str) -> PathBuf {
+    prefix
+        .split('/')
+        .map(|segment| match segment {
+            "" => "%".to_string(),
+            "." => "%2E".to_string(),
+            ".." => "%2E%2E".to_string(),
+            segment => segment
+                .replace('%', "%25")
+                .replace('\\', "%5C")
+                .replace(':', "%3A")
+                .replace('\0', "%00"),
+        })
+        .collect()
+}
+
+fn quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// Counts the bytes written through it, e.g. the compressed size of a log file.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W, count: Arc<AtomicU64>) -> Self {
+        CountingWriter { inner, count }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.fetch_add(written as u64, Ordering::SeqCst);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Syncs the file to disk when flushed, so flushed data survives a crash of the machine.
+pub struct SyncOnFlush {
+    file: File,
+}
+
+impl SyncOnFlush {
+    pub fn new(file: File) -> Self {
+        SyncOnFlush { file }
+    }
+}
+
+impl Write for SyncOnFlush {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::reader::LogReader;
+    use std::cell::RefCell;
+    use std::io::{BufReader, Cursor};
+    use std::rc::Rc;
+
+    /// A sink which stays readable after the writer on top of it is dropped.
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dropped_writer_leaves_a_decodable_log() {
+        let msg: MqttMessage = serde_json::from_str(
+            r#"{"time":1000.0,"qos":0,"retain":false,"topic":"a/b","msg":"payload"}"#,
+        )
+        .unwrap();
+
+        for (codec, format) in [
+            (Codec::Zstd, Format::Json),
+            (Codec::Zstd, Format::MsgPack),
+            (Codec::Gzip, Format::Json),
+            (Codec::Lz4, Format::Json),
+        ] {
+            let sink = SharedSink::default();
+            let mut writer = format
+                .writer(codec.encoder(sink.clone(), 9, 1).unwrap(), false)
+                .unwrap();
+            for _ in 0..1000 {
+                writer.write_message(&msg).unwrap();
+            }
+            // Ended mid-stream without a flush, like logging stopped by an error
+            drop(writer);
+
+            let log = sink.0.borrow().clone();
+            let reader = codec.decoder(Cursor::new(log)).unwrap();
+            let messages = LogReader::new(BufReader::new(reader))
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            assert_eq!(messages.len(), 1000, "{:?} {:?}", codec, format);
+            assert_eq!(messages[999].topic, "a/b");
+        }
+    }
+}
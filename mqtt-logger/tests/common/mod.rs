@@ -0,0 +1,53 @@
+//! A fake broker for running the logger against.
+
+use std::io::{self, Read, Write};
+
+/// Reads an MQTT packet, returning its type and body.
+pub fn read_packet(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    let packet_type = byte[0] >> 4;
+
+    let mut len = 0;
+    for shift in (0..28).step_by(7) {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+
+    Ok((packet_type, body))
+}
+
+/// Acknowledges the connection and subscription of the logger and publishes `messages` test
+/// messages on `a/b`.
+pub fn fake_broker(mut stream: impl Read + Write, messages: usize) -> io::Result<()> {
+    read_packet(&mut stream)?;
+    stream.write_all(&[0x20, 0x02, 0x00, 0x00])?;
+
+    let (_, subscribe) = read_packet(&mut stream)?;
+    stream.write_all(&[0x90, 0x03, subscribe[0], subscribe[1], 0x00])?;
+
+    for i in 0..messages {
+        let payload = format!("message {}", i);
+        let mut publish = vec![0x30, (2 + 3 + payload.len()) as u8, 0x00, 0x03];
+        publish.extend_from_slice(b"a/b");
+        publish.extend_from_slice(payload.as_bytes());
+        stream.write_all(&publish)?;
+    }
+    stream.flush()?;
+
+    // Answer pings until the logger disconnects
+    while let Ok((packet_type, _)) = read_packet(&mut stream) {
+        if packet_type == 12 {
+            stream.write_all(&[0xd0, 0x00])?;
+            stream.flush()?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Stopping the logger with a signal, the way service managers do, finishes the log file.
+#![cfg(unix)]
+
+mod common;
+
+use common::fake_broker;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::LogReader;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MESSAGES: usize = 100;
+
+#[test]
+fn sigterm_finishes_the_log_file() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let broker = thread::spawn(move || fake_broker(listener.accept()?.0, MESSAGES));
+
+    let output = std::env::temp_dir().join(format!(
+        "mqtt-logger-sigterm-{}.json.zst",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&output);
+
+    let mut logger = Command::new(env!("CARGO_BIN_EXE_mqtt-logger"))
+        .args(["--server", "127.0.0.1", "--port", &port.to_string()])
+        .arg("--no-progress")
+        .arg(&output)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // The messages are still in the write buffers, only a clean shutdown writes them
+    thread::sleep(Duration::from_secs(1));
+    let killed = Command::new("kill")
+        .args(["-TERM", &logger.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = logger.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            logger.kill().unwrap();
+            panic!("The logger did not stop on SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+    broker.join().unwrap().unwrap();
+
+    let decoder = Codec::Zstd.decoder(File::open(&output).unwrap()).unwrap();
+    let messages = LogReader::new(BufReader::new(decoder))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    assert_eq!(messages.len(), MESSAGES);
+    assert_eq!(messages[MESSAGES - 1].topic, "a/b");
+}
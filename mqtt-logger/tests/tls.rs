@@ -0,0 +1,107 @@
+//! Logging from a broker whose certificate is signed by a self-signed CA given by --ca-cert.
+
+mod common;
+
+use common::fake_broker;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::LogReader;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use rustls::{NoClientAuth, ServerConfig, ServerSession, StreamOwned};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+
+const MESSAGES: usize = 5;
+
+fn cert_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/certs")
+        .join(name)
+}
+
+/// The TLS configuration of a broker with the certificate of `localhost`.
+fn server_config() -> Arc<ServerConfig> {
+    let certs = certs(&mut BufReader::new(
+        File::open(cert_path("localhost.pem")).unwrap(),
+    ))
+    .unwrap();
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(
+        File::open(cert_path("localhost.key")).unwrap(),
+    ))
+    .unwrap();
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, keys.remove(0)).unwrap();
+
+    Arc::new(config)
+}
+
+/// Runs the logger against a TLS broker until it logged the test messages, returning whether
+/// it succeeded and the log file.
+fn log_over_tls(name: &str, args: &[&str]) -> (bool, PathBuf) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = server_config();
+    let broker = thread::spawn(move || {
+        let (tcp, _) = listener.accept()?;
+        fake_broker(StreamOwned::new(ServerSession::new(&config), tcp), MESSAGES)
+    });
+
+    let output = std::env::temp_dir().join(format!(
+        "mqtt-logger-tls-{}-{}.json.zst",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&output);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_mqtt-logger"))
+        .args(["--server", "localhost", "--port", &port.to_string()])
+        .args([
+            "--max-messages",
+            &MESSAGES.to_string(),
+            "--max-reconnects",
+            "1",
+        ])
+        .args(["--duration", "10", "--no-progress"])
+        .args(args)
+        .arg(&output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+
+    // The broker ends with the connection, or with the rejected handshake
+    let _ = broker.join().unwrap();
+
+    (status.success(), output)
+}
+
+#[test]
+fn self_signed_ca() {
+    let ca = cert_path("ca.pem");
+    let (success, output) = log_over_tls("ca", &["--ca-cert", ca.to_str().unwrap()]);
+    assert!(success);
+
+    let decoder = Codec::Zstd.decoder(File::open(&output).unwrap()).unwrap();
+    let messages = LogReader::new(BufReader::new(decoder))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    std::fs::remove_file(&output).unwrap();
+
+    assert_eq!(messages.len(), MESSAGES);
+    assert_eq!(messages[0].topic, "a/b");
+}
+
+#[test]
+fn unknown_ca_is_rejected() {
+    // The broker certificate is not signed by the CA of the platform
+    let (success, output) = log_over_tls("no-ca", &["--tls"]);
+    let _ = std::fs::remove_file(&output);
+
+    assert!(!success);
+}
@@ -0,0 +1,447 @@
+use anyhow::anyhow;
+use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::{LogReader, ReadError};
+use mqtt_logger::writer::{Format, MessageWriter};
+use mqtt_logger::MqttMessage;
+use simple_logger::SimpleLogger;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+type Input = LogReader<BufReader<Box<dyn Read + Send>>>;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-merge",
+    about = "Merges logged MQTT streams into one, ordered by time"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Input log files, each ordered by time as written by the logger, up to --jitter
+    #[structopt(env = "INPUT", parse(from_os_str), required = true, min_values = 2)]
+    inputs: Vec<PathBuf>,
+
+    /// Output log file, in the format of the first input and compressed by its extension: .zst,
+    /// .gz or .lz4
+    #[structopt(short, long, env = "OUTPUT", parse(from_os_str))]
+    output: PathBuf,
+
+    /// The input files are ZSTD compressed log files, or uncompressed with false. Without it
+    /// the compression of each is told by its extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+
+    /// Seconds by which the messages of an input may be out of order, e.g. 0.5 for gateways
+    /// whose clocks or queues reorder messages a little. Messages are held back for this long
+    /// to sort them, later ones are written out of order and counted
+    #[structopt(long, env = "JITTER", default_value = "0")]
+    jitter: f64,
+
+    /// The compression level of the merged log file
+    #[structopt(long, env = "COMPRESSION_LEVEL", default_value = "9")]
+    compression_level: i32,
+}
+
+/// A message read ahead, ordered so the heap pops the earliest message first, and of equal
+/// times the one from the input given first, then the one read first.
+struct Head {
+    time: f64,
+    input: usize,
+    seq: u64,
+    msg: MqttMessage,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .time
+            .total_cmp(&self.time)
+            .then(other.input.cmp(&self.input))
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// An input with the latest time read from it, `None` once it is read to the end.
+struct Source<'a> {
+    path: &'a Path,
+    log_file: Input,
+    latest: Option<f64>,
+}
+
+impl Source<'_> {
+    /// Whether the input may still have messages at or before `time`.
+    fn behind(&self, time: f64) -> bool {
+        self.latest.is_some_and(|latest| latest <= time)
+    }
+
+    /// Reads messages into the heap until one is later than `time`, or the input ends.
+    fn read_past(&mut self, time: f64, input: usize, heads: &mut BinaryHeap<Head>, seq: &mut u64) {
+        while self.behind(time) {
+            match next_message(self.path, &mut self.log_file) {
+                Some(msg) => {
+                    let time = msg.time.secs();
+                    self.latest = self.latest.map(|latest| latest.max(time));
+                    heads.push(Head {
+                        time,
+                        input,
+                        seq: *seq,
+                        msg,
+                    });
+                    *seq += 1;
+                }
+                None => self.latest = None,
+            }
+        }
+    }
+}
+
+/// The next readable message of an input, corrupted records are skipped.
+fn next_message(path: &Path, input: &mut Input) -> Option<MqttMessage> {
+    for msg in input {
+        match msg {
+            Ok(msg) => return Some(msg),
+            Err(ReadError::Corrupt(e)) => {
+                error!("Corrupted dataset in '{}': {}", path.display(), e);
+            }
+            Err(ReadError::Io(e)) => {
+                error!(
+                    "Unable to read further from '{}', it may be truncated: {}",
+                    path.display(),
+                    e
+                );
+                return None;
+            }
+        }
+    }
+
+    None
+}
+
+/// Writes the messages of the inputs ordered by time, returning the number of messages and of
+/// those written late, being more than `jitter` seconds out of order.
+fn merge(
+    sources: &mut [Source],
+    jitter: f64,
+    writer: &mut dyn MessageWriter,
+) -> anyhow::Result<(u64, u64)> {
+    // Only the messages within the jitter of the earliest one are held in memory
+    let mut heads = BinaryHeap::new();
+    let mut seq = 0u64;
+    for (input, source) in sources.iter_mut().enumerate() {
+        source.read_past(f64::NEG_INFINITY, input, &mut heads, &mut seq);
+    }
+
+    let mut messages = 0u64;
+    let mut late = 0u64;
+    let mut last_time = f64::NEG_INFINITY;
+    while let Some(earliest) = heads.peek().map(|head| head.time) {
+        // An earlier message may still follow within the jitter in an input not read that far
+        let until = earliest + jitter;
+        if sources.iter().any(|source| source.behind(until)) {
+            for (input, source) in sources.iter_mut().enumerate() {
+                source.read_past(until, input, &mut heads, &mut seq);
+            }
+            continue;
+        }
+
+        let Head { time, msg, .. } = heads.pop().unwrap();
+        if time < last_time {
+            debug!(
+                "Message on topic '{}' is {}s out of order",
+                msg.topic,
+                last_time - time
+            );
+            late += 1;
+        }
+        last_time = last_time.max(time);
+
+        writer.write_message(&msg)?;
+        messages += 1;
+    }
+
+    Ok((messages, late))
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    if opt.jitter.is_nan() || opt.jitter < 0. {
+        return Err(anyhow!("The jitter needs to be 0 seconds or more"));
+    }
+
+    let mut sources = Vec::new();
+    for path in &opt.inputs {
+        if opt.output.exists() && opt.output.canonicalize()? == path.canonicalize()? {
+            return Err(anyhow!(
+                "The merged log can not overwrite the input '{}'",
+                path.display()
+            ));
+        }
+
+        let codec = Codec::of_input(path, opt.zstd);
+        sources.push(Source {
+            path,
+            log_file: LogReader::new(BufReader::new(codec.decoder(File::open(path)?)?))?,
+            latest: Some(f64::NEG_INFINITY),
+        });
+    }
+
+    let format = match sources[0].log_file {
+        LogReader::Json(..) => Format::Json,
+        LogReader::MsgPack(_) => Format::MsgPack,
+    };
+    let codec = Codec::from_path(&opt.output);
+
+    // Dropping the writer finishes the compressed stream
+    let mut writer = format.writer(
+        codec.encoder(
+            BufWriter::new(File::create(&opt.output)?),
+            opt.compression_level,
+            1,
+        )?,
+        false,
+    )?;
+
+    let (messages, late) = merge(&mut sources, opt.jitter, writer.as_mut())?;
+
+    writer.flush()?;
+    drop(writer);
+
+    println!(
+        "Merged {} messages from {} logs into '{}'",
+        messages,
+        opt.inputs.len(),
+        opt.output.display()
+    );
+
+    if late > 0 {
+        eprintln!(
+            "{} messages were more than the --jitter of {}s out of order and written late",
+            late, opt.jitter
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// Collects the topics of the merged messages.
+    struct Topics(Vec<String>);
+
+    impl MessageWriter for Topics {
+        fn write_message(&mut self, msg: &MqttMessage) -> anyhow::Result<usize> {
+            self.0.push(msg.topic.clone());
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An input logging each topic at its time.
+    fn source(messages: &[(f64, &str)]) -> Source<'static> {
+        let log: String = messages
+            .iter()
+            .map(|(time, topic)| {
+                format!(
+                    "{{\"time\":{},\"qos\":0,\"retain\":false,\"topic\":\"{}\",\"msg_b64\":\"\"}}\n",
+                    time, topic
+                )
+            })
+            .collect();
+        let inner: Box<dyn Read + Send> = Box::new(Cursor::new(log.into_bytes()));
+
+        Source {
+            path: Path::new("in.json"),
+            log_file: LogReader::new(BufReader::new(inner)).unwrap(),
+            latest: Some(f64::NEG_INFINITY),
+        }
+    }
+
+    /// A long log made up as it is read, counting the messages read from all of them.
+    struct Generated {
+        time: f64,
+        end: f64,
+        line: Cursor<Vec<u8>>,
+        read: Arc<AtomicU64>,
+    }
+
+    impl Read for Generated {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.line.position() == self.line.get_ref().len() as u64 && self.time < self.end {
+                let line = format!(
+                    "{{\"time\":{},\"qos\":0,\"retain\":false,\"topic\":\"a\",\"msg_b64\":\"\"}}\n",
+                    self.time
+                );
+                self.line = Cursor::new(line.into_bytes());
+                self.time += 1.;
+                self.read.fetch_add(1, Ordering::SeqCst);
+            }
+
+            self.line.read(buf)
+        }
+    }
+
+    /// Checks that the messages read are written soon after.
+    struct Held {
+        read: Arc<AtomicU64>,
+        written: u64,
+        most: u64,
+    }
+
+    impl MessageWriter for Held {
+        fn write_message(&mut self, _msg: &MqttMessage) -> anyhow::Result<usize> {
+            self.written += 1;
+            self.most = self
+                .most
+                .max(self.read.load(Ordering::SeqCst) - self.written);
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn merged(mut sources: Vec<Source>, jitter: f64) -> (Vec<String>, u64) {
+        let mut topics = Topics(Vec::new());
+        let (messages, late) = merge(&mut sources, jitter, &mut topics).unwrap();
+        assert_eq!(messages as usize, topics.0.len());
+
+        (topics.0, late)
+    }
+
+    #[test]
+    fn interleaves_by_time() {
+        let (topics, late) = merged(
+            vec![
+                source(&[(1., "a1"), (3., "a3"), (5., "a5")]),
+                source(&[(2., "b2"), (4., "b4"), (6., "b6"), (7., "b7")]),
+            ],
+            0.,
+        );
+
+        assert_eq!(topics, ["a1", "b2", "a3", "b4", "a5", "b6", "b7"]);
+        assert_eq!(late, 0);
+    }
+
+    #[test]
+    fn equal_times_in_input_order() {
+        let (topics, late) = merged(
+            vec![
+                source(&[(1., "a1"), (2., "a2")]),
+                source(&[(1., "b1"), (1., "b1'"), (2., "b2")]),
+                source(&[(1., "c1"), (2., "c2")]),
+            ],
+            0.,
+        );
+
+        assert_eq!(topics, ["a1", "b1", "b1'", "c1", "a2", "b2", "c2"]);
+        assert_eq!(late, 0);
+    }
+
+    #[test]
+    fn sorts_within_the_jitter() {
+        let (topics, late) = merged(
+            vec![
+                source(&[(1., "a1"), (3., "a3"), (2.5, "a2.5"), (4., "a4")]),
+                source(&[(2., "b2"), (2.75, "b2.75")]),
+            ],
+            1.,
+        );
+
+        assert_eq!(topics, ["a1", "b2", "a2.5", "b2.75", "a3", "a4"]);
+        assert_eq!(late, 0);
+    }
+
+    #[test]
+    fn counts_messages_later_than_the_jitter() {
+        let (topics, late) = merged(
+            vec![
+                source(&[(1., "a1"), (3., "a3"), (4., "a4"), (2.5, "a2.5")]),
+                source(&[(2., "b2")]),
+            ],
+            0.25,
+        );
+
+        // Written as they come, after the later message already written
+        assert_eq!(topics, ["a1", "b2", "a3", "a2.5", "a4"]);
+        assert_eq!(late, 1);
+    }
+
+    #[test]
+    fn empty_inputs() {
+        let (topics, _) = merged(vec![source(&[]), source(&[(1., "b1")])], 0.);
+
+        assert_eq!(topics, ["b1"]);
+    }
+
+    #[test]
+    fn holds_only_messages_within_the_jitter() {
+        let read = Arc::new(AtomicU64::new(0));
+        let mut sources: Vec<Source> = [0., 0.5]
+            .iter()
+            .map(|&start| {
+                let inner: Box<dyn Read + Send> = Box::new(Generated {
+                    time: start,
+                    end: 100_000.,
+                    line: Cursor::new(Vec::new()),
+                    read: read.clone(),
+                });
+                Source {
+                    path: Path::new("in.json"),
+                    log_file: LogReader::new(BufReader::new(inner)).unwrap(),
+                    latest: Some(f64::NEG_INFINITY),
+                }
+            })
+            .collect();
+
+        let mut held = Held {
+            read: read.clone(),
+            written: 0,
+            most: 0,
+        };
+        let (messages, _) = merge(&mut sources, 2., &mut held).unwrap();
+
+        assert_eq!(messages, 200_000);
+        // Besides the messages within the jitter, only those in the read buffers are held
+        assert!(held.most < 500, "{} messages were held", held.most);
+    }
+}
@@ -0,0 +1,330 @@
+use anyhow::anyhow;
+use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::index::{read_index, IndexEntry, IndexWriter};
+use mqtt_logger::reader::{LogReader, ReadError};
+use mqtt_logger::writer::{Format, MessageWriter};
+use simple_logger::SimpleLogger;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-repair",
+    about = "Recovers the readable messages of a damaged log, e.g. after the logger was killed. \
+             The index of a log with frames is rebuilt"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Damaged log file
+    #[structopt(env = "INPUT", parse(from_os_str))]
+    input: PathBuf,
+
+    /// Repaired log file, written in the format and compression of the input
+    #[structopt(env = "OUTPUT", parse(from_os_str))]
+    output: PathBuf,
+
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+
+    /// The compression level of the repaired log file
+    #[structopt(long, env = "COMPRESSION_LEVEL", default_value = "9")]
+    compression_level: i32,
+}
+
+/// What was recovered of a damaged log.
+struct Repaired {
+    messages: u64,
+    /// Uncompressed bytes of the recovered messages
+    bytes: u64,
+    /// Frames of the rebuilt index, if the log has one
+    frames: Option<u64>,
+    /// Corrupted records left out
+    dropped: u64,
+    /// Whether reading stopped at a decode error, losing the rest of the log
+    truncated: bool,
+}
+
+/// Writes the readable messages of the log `input` to `output`, rebuilding its index.
+fn repair(
+    input: &Path,
+    codec: Codec,
+    output: &Path,
+    compression_level: i32,
+) -> anyhow::Result<Repaired> {
+    let log_file = LogReader::new(BufReader::new(codec.decoder(File::open(input)?)?))?;
+    let format = match log_file {
+        LogReader::Json(..) => Format::Json,
+        LogReader::MsgPack(_) => Format::MsgPack,
+    };
+
+    // A new frame is started by appending, after the previous writer finished its frame
+    let open = |append: bool| -> anyhow::Result<Box<dyn MessageWriter>> {
+        let file = if append {
+            OpenOptions::new().append(true).open(output)?
+        } else {
+            File::create(output)?
+        };
+        format.writer(
+            codec.encoder(BufWriter::new(file), compression_level, 1)?,
+            append,
+        )
+    };
+    let mut writer = open(false)?;
+
+    // The repaired log gets frames starting at the same times, as far as it was recovered
+    let frame_times: Vec<f64> = match read_index(input)? {
+        Some(entries) if format == Format::Json => entries.iter().map(|e| e.time).collect(),
+        _ => Vec::new(),
+    };
+    let mut index = if frame_times.is_empty() {
+        None
+    } else {
+        Some(IndexWriter::open(output, false)?)
+    };
+    let mut next_frame = 0;
+    let mut frames = 0;
+
+    let mut messages = 0u64;
+    let mut bytes = 0u64;
+    let mut dropped = 0u64;
+    let mut truncated = false;
+
+    // A half written last record is reported as corrupted, as the stream ends after it
+    for msg in log_file {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(ReadError::Corrupt(e)) => {
+                warn!("Dropped a corrupted record: {}", e);
+                dropped += 1;
+                continue;
+            }
+            Err(ReadError::Io(e)) => {
+                info!("Stopped reading at a decode error: {}", e);
+                truncated = true;
+                break;
+            }
+        };
+
+        if let Some(index) = &mut index {
+            let time = msg.time.secs();
+            let frames_passed = frame_times[next_frame..]
+                .iter()
+                .take_while(|&&start| time >= start)
+                .count();
+            if frames_passed > 0 {
+                next_frame += frames_passed;
+
+                if messages > 0 {
+                    writer.flush()?;
+                    drop(writer);
+                    writer = open(true)?;
+                }
+                index.add(&IndexEntry {
+                    offset: fs::metadata(output)?.len(),
+                    time,
+                    messages,
+                })?;
+                frames += 1;
+            }
+        }
+
+        bytes += writer.write_message(&msg)? as u64;
+        messages += 1;
+    }
+
+    writer.flush()?;
+    drop(writer);
+
+    Ok(Repaired {
+        messages,
+        bytes,
+        frames: index.map(|_| frames),
+        dropped,
+        truncated,
+    })
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    let input = opt.input;
+    let codec = Codec::of_input(&input, opt.zstd);
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    if opt.output.exists() && opt.output.canonicalize()? == input.canonicalize()? {
+        return Err(anyhow!(
+            "The repaired log must be written to another file than '{}'",
+            input.display()
+        ));
+    }
+
+    let repaired = repair(&input, codec, &opt.output, opt.compression_level)?;
+
+    println!(
+        "Recovered {} messages ({:.2} MB uncompressed) into '{}'",
+        repaired.messages,
+        repaired.bytes as f64 / 1024. / 1024.,
+        opt.output.display()
+    );
+    if let Some(frames) = repaired.frames {
+        println!("Rebuilt the index of {} frames", frames);
+    }
+    if repaired.dropped > 0 {
+        println!("Dropped {} corrupted records", repaired.dropped);
+    }
+    if repaired.truncated {
+        println!("The log file was truncated, the messages after the damage are lost");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    fn line(time: u64) -> String {
+        format!(
+            "{{\"time\":{}.0,\"qos\":0,\"retain\":false,\"topic\":\"a/b\",\"msg\":\"{}\"}}\n",
+            time, time
+        )
+    }
+
+    fn lines(times: std::ops::Range<u64>) -> String {
+        times.map(line).collect()
+    }
+
+    /// Writes a log of ZSTD frames with an index, each frame given by the time of its first
+    /// message and its lines.
+    fn write_log(path: &Path, frames: &[(f64, String)]) {
+        File::create(path).unwrap();
+        let mut index = IndexWriter::open(path, false).unwrap();
+
+        let mut messages = 0;
+        for (time, lines) in frames {
+            index
+                .add(&IndexEntry {
+                    offset: fs::metadata(path).unwrap().len(),
+                    time: *time,
+                    messages,
+                })
+                .unwrap();
+
+            let file = OpenOptions::new().append(true).open(path).unwrap();
+            let mut encoder = Codec::Zstd.encoder(file, 3, 1).unwrap();
+            encoder.write_all(lines.as_bytes()).unwrap();
+            drop(encoder);
+            messages += lines.lines().count() as u64;
+        }
+    }
+
+    /// The decoded log file, and the time of the message at the start of each frame.
+    fn read_log(path: &Path) -> (String, Vec<(f64, u64)>) {
+        let mut log = String::new();
+        Codec::Zstd
+            .decoder(File::open(path).unwrap())
+            .unwrap()
+            .read_to_string(&mut log)
+            .unwrap();
+
+        let mut frames = Vec::new();
+        for entry in read_index(path).unwrap().unwrap() {
+            let mut file = File::open(path).unwrap();
+            file.seek(SeekFrom::Start(entry.offset)).unwrap();
+            let mut frame = BufReader::new(Codec::Zstd.decoder(file).unwrap());
+            let msg = LogReader::new(&mut frame).unwrap().next().unwrap().unwrap();
+            assert_eq!(msg.time.secs(), entry.time);
+            frames.push((entry.time, entry.messages));
+        }
+
+        (log, frames)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mqtt-repair-{}-{}", std::process::id(), name))
+    }
+
+    fn remove(path: &Path) {
+        fs::remove_file(path).unwrap();
+        fs::remove_file(mqtt_logger::index::index_path(path)).unwrap();
+    }
+
+    #[test]
+    fn half_written_last_line() {
+        let input = temp_path("half-line.json.zst");
+        let output = temp_path("half-line-repaired.json.zst");
+        let half = line(25);
+        write_log(
+            &input,
+            &[
+                (0., lines(0..10)),
+                (10., lines(10..20)),
+                (20., lines(20..25) + &half[..half.len() / 2]),
+            ],
+        );
+
+        let repaired = repair(&input, Codec::Zstd, &output, 3).unwrap();
+        let (log, frames) = read_log(&output);
+        remove(&input);
+        remove(&output);
+
+        assert_eq!(repaired.messages, 25);
+        assert_eq!(repaired.dropped, 1);
+        assert!(!repaired.truncated);
+        assert_eq!(log, lines(0..25));
+        assert_eq!(repaired.bytes, log.len() as u64);
+        assert_eq!(repaired.frames, Some(3));
+        assert_eq!(frames, [(0., 0), (10., 10), (20., 20)]);
+    }
+
+    #[test]
+    fn log_cut_mid_frame() {
+        let input = temp_path("cut.json.zst");
+        let output = temp_path("cut-repaired.json.zst");
+        write_log(
+            &input,
+            &[
+                (0., lines(0..10)),
+                (10., lines(10..20)),
+                (20., lines(20..30)),
+            ],
+        );
+        let len = fs::metadata(&input).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&input)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+
+        let repaired = repair(&input, Codec::Zstd, &output, 3).unwrap();
+        let (log, frames) = read_log(&output);
+        remove(&input);
+        remove(&output);
+
+        assert_eq!(repaired.messages, 20);
+        assert_eq!(repaired.dropped, 0);
+        assert!(repaired.truncated);
+        assert_eq!(log, lines(0..20));
+        assert_eq!(repaired.bytes, log.len() as u64);
+        assert_eq!(repaired.frames, Some(2));
+        assert_eq!(frames, [(0., 0), (10., 10)]);
+    }
+}
@@ -1,12 +1,15 @@
+use anyhow::anyhow;
 use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::{LogReader, ReadError};
+use mqtt_logger::MqttMessage;
 use regex::RegexSet;
 use rumqttc::{Client, ClientConfig, MqttOptions, TlsConfiguration, Transport};
-use serde::Deserialize;
 use simple_logger::SimpleLogger;
-use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -16,15 +19,159 @@ use structopt::StructOpt;
 // Reference:
 // {"time": 1611137748.0325797, "qos": 0, "retain": true, "topic": "kvarntorp-test/gateway/165640a7e023861a/nodeversion", "msg_b64": "IjAuMi4xNSI="}
 
-#[derive(Deserialize, Debug)]
-struct MqttMessage {
-    time: f64,
-    qos: u8,
-    retain: bool,
-    topic: String,
-    msg_b64: String,
+/// A topic rewrite rule, a trailing `#` on both sides keeps the rest of the topic.
+#[derive(Debug)]
+struct Remap {
+    from: String,
+    to: String,
 }
 
+impl FromStr for Remap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Remap '{}' needs to be in the form FROM=TO", s))?;
+
+        if from.ends_with('#') != to.ends_with('#') {
+            return Err(anyhow!(
+                "Remap '{}' needs a trailing '#' on both sides or neither",
+                s
+            ));
+        }
+
+        Ok(Remap {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+impl Remap {
+    fn apply(&self, topic: &str) -> Option<String> {
+        match (self.from.strip_suffix('#'), self.to.strip_suffix('#')) {
+            (Some(from), Some(to)) => {
+                if let Some(rest) = topic.strip_prefix(from) {
+                    Some(format!("{}{}", to, rest))
+                } else if topic == from.trim_end_matches('/') {
+                    // 'a/#' also matches 'a'
+                    Some(to.trim_end_matches('/').to_string())
+                } else {
+                    None
+                }
+            }
+            _ if topic == self.from => Some(self.to.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The next readable message of the log, corrupted records are skipped.
+fn next_message<R: BufRead>(log_file: &mut LogReader<R>) -> Option<MqttMessage> {
+    for msg in log_file {
+        match msg {
+            Ok(msg) => return Some(msg),
+            Err(ReadError::Corrupt(e)) => {
+                error!("Corrupted dataset: {}", e);
+            }
+            // A log that was not closed properly ends in a truncated ZSTD frame, replay what we
+            // have instead of retrying the broken read forever
+            Err(ReadError::Io(e)) => {
+                error!(
+                    "Unable to read further from the log file, it may be truncated: {}",
+                    e
+                );
+                return None;
+            }
+        }
+    }
+
+    None
+}
+
+/// The topic rewritten by the first matching rule, or as it is without one.
+fn remap(remaps: &[Remap], topic: String) -> String {
+    remaps
+        .iter()
+        .find_map(|remap| remap.apply(&topic))
+        .unwrap_or(topic)
+}
+
+/// The timing of the replay, telling when each message of the log is due.
+struct Schedule {
+    speed: f64,
+    max_gap: Option<f64>,
+    /// Seconds of the log to skip before publishing
+    skip: f64,
+    seek_done: bool,
+    /// Log times of the first message published and of the previous message
+    start: Option<f64>,
+    previous: f64,
+    /// Log time left out by shortening gaps with --max-gap
+    shortened: f64,
+}
+
+impl Schedule {
+    fn new(speed: f64, max_gap: Option<f64>, skip: f64) -> Self {
+        Schedule {
+            speed,
+            max_gap,
+            skip,
+            seek_done: skip == 0.,
+            start: None,
+            previous: 0.,
+            shortened: 0.,
+        }
+    }
+
+    /// Moves on to the message logged at `time`, returning the seconds into the replay at which
+    /// the message after it is due. None while seeking, and for the first message published.
+    fn advance(&mut self, time: f64) -> Option<f64> {
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some(time);
+                self.previous = time;
+                return None;
+            }
+        };
+
+        if let Some(max_gap) = self.max_gap {
+            let gap = time - self.previous;
+            if self.seek_done && gap / self.speed > max_gap {
+                debug!("Shortening a gap of {:.1} seconds in the log", gap);
+                self.shortened += gap - max_gap * self.speed;
+            }
+        }
+        self.previous = time;
+
+        // Out of order timestamps give a negative duration, which is clamped by `wait`
+        let log_duration = time - start - self.shortened;
+        if !self.seek_done {
+            if log_duration >= self.skip {
+                self.seek_done = true;
+                self.start = Some(time);
+
+                info!("Seek until timestamp {} seconds completed!", self.skip);
+            }
+
+            return None;
+        }
+
+        Some(log_duration / self.speed)
+    }
+}
+
+/// The seconds to wait for a message due `due` seconds into the replay, `elapsed` seconds in.
+fn wait(due: f64, elapsed: f64) -> f64 {
+    f64::max(due - elapsed, 0.0)
+}
+
+/// Slower replays would wait days between messages, faster ones are as fast as possible anyway
+const MIN_SPEED: f64 = 0.001;
+const MAX_SPEED: f64 = 1000.0;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "mqtt-replay", about = "A replay of an logged MQTT stream")]
 struct Opt {
@@ -44,10 +191,28 @@ struct Opt {
     #[structopt(short, long, env = "PORT")]
     port: Option<u16>,
 
-    /// Replay speed multiplier
+    /// Replay speed multiplier, e.g. 24 plays a day back in an hour and 0.1 slows it down tenfold.
+    /// Limited to between 0.001 and 1000
     #[structopt(long, env = "SPEED", default_value = "1.0")]
     speed: f64,
 
+    /// Publish at a fixed rate in messages per second, ignoring the logged timing
+    #[structopt(long, env = "RATE", conflicts_with = "asap")]
+    rate: Option<f64>,
+
+    /// Publish as fast as possible, ignoring the logged timing
+    #[structopt(long)]
+    asap: bool,
+
+    /// Publish with the logged timing, scaled by --speed. This is the default without --asap or
+    /// --rate, the flag only makes it explicit
+    #[structopt(long, conflicts_with_all = &["asap", "rate"])]
+    realtime: bool,
+
+    /// Longest wait between two messages in seconds, longer gaps in the log are shortened to this
+    #[structopt(long, env = "MAX_GAP")]
+    max_gap: Option<f64>,
+
     /// Skip for certain amount of time
     #[structopt(long, env = "SKIP", default_value = "0.0")]
     skip: f64,
@@ -56,15 +221,21 @@ struct Opt {
     #[structopt(long, use_delimiter = true, env = "TOPIC_REJECTION_REGEX")]
     filter_topic: Vec<String>,
 
+    /// Rewrite topics before publishing, e.g. 'prod/#=staging/#' or 'a/b=c/d'. Can be given
+    /// multiple times, the first matching rule is used
+    #[structopt(long, env = "REMAP")]
+    remap: Vec<Remap>,
+
     /// TLS enable
-    #[structopt(long, env = "TLS")]
+    #[structopt(long)]
     tls: bool,
 
     /// Path to custom CA file
     #[structopt(long, env = "CUSTOM_CA")]
     custom_ca: Option<PathBuf>,
 
-    /// The file is a ZSTD compressed log file
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
     #[structopt(long, env = "ZSTD")]
     zstd: Option<bool>,
 }
@@ -79,11 +250,14 @@ fn main() -> anyhow::Result<()> {
     } else {
         1883
     });
-    let speed = opt.speed;
+    let mut speed = opt.speed;
+    let rate = opt.rate;
+    let asap = opt.asap;
     let skip_to_time = opt.skip;
-    let zstd = opt
-        .zstd
-        .unwrap_or(input.extension() == Some(OsStr::new("zst")));
+    let max_gap = opt.max_gap;
+    // The logged timing is kept unless asked otherwise, --realtime only says so
+    let _ = opt.realtime;
+    let codec = Codec::of_input(&input, opt.zstd);
 
     match opt.verbosity {
         0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
@@ -94,10 +268,25 @@ fn main() -> anyhow::Result<()> {
     }
     .init()?;
 
-    assert!(
-        opt.speed > 0.0,
-        "Playback speed multiplier needs to be larger than 0"
-    );
+    if opt.speed.is_nan() || opt.speed <= 0.0 {
+        return Err(anyhow!("--speed needs to be larger than 0"));
+    }
+
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        warn!(
+            "Playback speed multiplier {} is out of range, using {}",
+            opt.speed, speed
+        );
+    }
+
+    if rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+        return Err(anyhow!("--rate needs to be larger than 0"));
+    }
+
+    if max_gap.is_some_and(|max_gap| max_gap.is_nan() || max_gap < 0.0) {
+        return Err(anyhow!("--max-gap can not be negative"));
+    }
 
     // println!("filter_topic: {:#?}", opt.filter_topic);
 
@@ -113,7 +302,7 @@ fn main() -> anyhow::Result<()> {
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or(Duration::new(0, 1))
         .subsec_nanos();
-    let mut mqtt_options = MqttOptions::new(&format!("mqtt-logger-sub{}", nanos), &server, port);
+    let mut mqtt_options = MqttOptions::new(format!("mqtt-logger-sub{}", nanos), &server, port);
 
     // Check for custom CA file
     let custom_ca = if let Some(custom_ca_path) = &opt.custom_ca {
@@ -173,47 +362,28 @@ fn main() -> anyhow::Result<()> {
         info!("The following topic filters are active: {}", filters);
     }
 
+    let remaps = opt.remap;
+    for remap in &remaps {
+        info!("Remapping topic '{}' to '{}'", remap.from, remap.to);
+    }
+
     let start_time_local = SystemTime::now();
-    let mut start_time_log: f64 = 0.0;
-    let mut first_message = true;
-    let mut seek_done = skip_to_time == 0.;
+    let mut schedule = Schedule::new(speed, max_gap, skip_to_time);
+    let mut sent: u64 = 0;
 
-    let mut f = BufReader::new(File::open(&input)?);
-    let mut zf = zstd::Decoder::new(BufReader::new(File::open(&input)?))?;
+    let mut log_file = LogReader::new(BufReader::new(codec.decoder(File::open(&input)?)?))?;
     let keep_running = Arc::new(AtomicBool::new(true));
     let thread_keep_running = keep_running.clone();
 
     thread::spawn(move || {
-        let log_file: &mut dyn std::io::Read = if zstd { &mut zf } else { &mut f };
-        let log_file = BufReader::new(log_file);
-
-        for line in log_file.lines() {
-            let line = if let Ok(line) = line {
-                line
-            } else {
-                continue;
-            };
-
-            trace!("{:?}", &line);
-
-            let msg: MqttMessage = match serde_json::from_str(&line) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    error!(
-                        "Corrupted dataset: Serde error with line '{}', error: {}",
-                        &line, e
-                    );
-                    continue;
-                }
-            };
-
+        while let Some(msg) = next_message(&mut log_file) {
             // Check for filtered message
             let filter_message = filter_topic
                 .as_ref()
                 .map(|re| re.is_match(&msg.topic))
                 .unwrap_or(false);
 
-            if seek_done && !filter_message {
+            if schedule.seek_done && !filter_message {
                 // Widefind messages
                 let qos = match rumqttc::qos(msg.qos) {
                     Ok(q) => q,
@@ -223,55 +393,63 @@ fn main() -> anyhow::Result<()> {
                     }
                 };
 
-                let b64 = match base64::decode(msg.msg_b64) {
+                let payload = match msg.payload.decode() {
                     Ok(b) => b,
                     Err(e) => {
-                        error!("Corrupted dataset: data is not base64 encoded '{}'", e);
+                        error!("Corrupted dataset: payload could not be decoded '{}'", e);
                         continue;
                     }
                 };
 
+                let topic = remap(&remaps, msg.topic);
+
                 mqtt_client
-                    .publish(msg.topic, qos, msg.retain, b64)
+                    .publish(topic, qos, msg.retain, payload)
                     .unwrap();
-            }
 
-            if first_message {
-                start_time_log = msg.time;
-                first_message = false;
-            } else {
-                let log_duration = msg.time - start_time_log;
-                let speed_log_duration = log_duration / speed;
+                sent += 1;
 
-                let duration_left = if !seek_done && log_duration < skip_to_time {
-                    0.
-                } else {
-                    if !seek_done {
-                        seek_done = true;
-                        start_time_log = msg.time;
-
-                        info!("Seek until timestamp {} seconds completed!", skip_to_time);
-
-                        0.
-                    } else {
-                        f64::max(
-                            speed_log_duration
-                                - SystemTime::now()
-                                    .duration_since(start_time_local)
-                                    .unwrap()
-                                    .as_secs_f64(),
-                            0.0,
-                        )
+                if let Some(rate) = rate {
+                    let duration_left = sent as f64 / rate
+                        - SystemTime::now()
+                            .duration_since(start_time_local)
+                            .unwrap()
+                            .as_secs_f64();
+
+                    if duration_left > 0.0 {
+                        thread::sleep(Duration::from_secs_f64(duration_left));
                     }
-                };
+                }
+            }
+
+            if let Some(due) = schedule.advance(msg.time.secs()) {
+                if !asap && rate.is_none() {
+                    let duration_left = wait(
+                        due,
+                        SystemTime::now()
+                            .duration_since(start_time_local)
+                            .unwrap()
+                            .as_secs_f64(),
+                    );
 
-                if duration_left > 0.0 {
-                    thread::sleep(Duration::from_secs_f64(duration_left));
+                    if duration_left > 0.0 {
+                        thread::sleep(Duration::from_secs_f64(duration_left));
+                    }
                 }
             }
         }
 
-        info!("Dataset completed, shutting down...");
+        let elapsed = SystemTime::now()
+            .duration_since(start_time_local)
+            .unwrap()
+            .as_secs_f64();
+
+        info!(
+            "Dataset completed, replayed {} messages in {:.2} seconds ({:.1} messages/s), shutting down...",
+            sent,
+            elapsed,
+            sent as f64 / elapsed.max(f64::EPSILON),
+        );
 
         thread_keep_running.store(false, Ordering::SeqCst);
     });
@@ -284,3 +462,189 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mqtt_logger::codec::Codec;
+    use std::cell::RefCell;
+    use std::io::{Cursor, Read, Write};
+    use std::rc::Rc;
+
+    /// A log of messages on topic `a` logged at `times`.
+    fn log(times: &[f64]) -> String {
+        times
+            .iter()
+            .map(|time| {
+                format!(
+                    "{{\"time\":{},\"qos\":0,\"retain\":false,\"topic\":\"a\",\"msg\":\"{}\"}}\n",
+                    time, time
+                )
+            })
+            .collect()
+    }
+
+    /// Replays a log without waiting, returning the payloads and the waits after them.
+    fn replay(log: impl Read + Send + 'static) -> Vec<(String, Option<f64>)> {
+        let mut log_file = LogReader::new(BufReader::new(log)).unwrap();
+        let mut schedule = Schedule::new(1., None, 0.);
+
+        let mut replayed = Vec::new();
+        while let Some(msg) = next_message(&mut log_file) {
+            let due = schedule.advance(msg.time.secs());
+            let payload = String::from_utf8(msg.payload.decode().unwrap()).unwrap();
+            replayed.push((payload, due.map(|due| wait(due, 0.))));
+        }
+
+        replayed
+    }
+
+    #[test]
+    fn replay_stops_at_a_truncated_line() {
+        let mut log = log(&[10., 12., 9.]);
+        log += r#"{"time":13,"qos":0,"ret"#;
+
+        // The earlier message is not waited for
+        assert_eq!(
+            replay(Cursor::new(log)),
+            [
+                ("10".to_string(), None),
+                ("12".to_string(), Some(2.)),
+                ("9".to_string(), Some(0.))
+            ]
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn replay_stops_at_a_truncated_frame() {
+        let times: Vec<f64> = (0..20_000).map(f64::from).collect();
+        let sink = SharedSink::default();
+        let mut encoder = Codec::Zstd.encoder(sink.clone(), 3, 1).unwrap();
+        encoder.write_all(log(&times).as_bytes()).unwrap();
+        drop(encoder);
+
+        let mut compressed = sink.0.borrow().clone();
+        compressed.truncate(compressed.len() - 10);
+        let replayed = replay(Codec::Zstd.decoder(Cursor::new(compressed)).unwrap());
+
+        assert!(!replayed.is_empty() && replayed.len() < times.len());
+        for (i, (payload, _)) in replayed.iter().enumerate() {
+            assert_eq!(payload, &i.to_string());
+        }
+    }
+
+    fn remaps(rules: &[&str]) -> Vec<Remap> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn remap_prefixes() {
+        let remaps = remaps(&["prod/#=staging/#"]);
+
+        assert_eq!(remap(&remaps, "prod/a/b".into()), "staging/a/b");
+        assert_eq!(remap(&remaps, "prod/".into()), "staging/");
+        assert_eq!(remap(&remaps, "prod".into()), "staging");
+        assert_eq!(remap(&remaps, "production/a".into()), "production/a");
+        assert_eq!(remap(&remaps, "a/prod/b".into()), "a/prod/b");
+    }
+
+    #[test]
+    fn remap_exact_topics() {
+        let remaps = remaps(&["a/b=c/d"]);
+
+        assert_eq!(remap(&remaps, "a/b".into()), "c/d");
+        assert_eq!(remap(&remaps, "a/b/c".into()), "a/b/c");
+        assert_eq!(remap(&remaps, "a".into()), "a");
+    }
+
+    #[test]
+    fn first_remap_wins() {
+        let remaps = remaps(&["prod/a=exact", "prod/#=staging/#", "prod/a/#=never/#"]);
+
+        assert_eq!(remap(&remaps, "prod/a".into()), "exact");
+        assert_eq!(remap(&remaps, "prod/a/b".into()), "staging/a/b");
+    }
+
+    #[test]
+    fn topics_without_a_remap_are_kept() {
+        let remaps = remaps(&["prod/#=staging/#", "a/b=c/d"]);
+
+        assert_eq!(remap(&remaps, "other/topic".into()), "other/topic");
+        assert_eq!(remap(&[], "prod/a".into()), "prod/a");
+    }
+
+    #[test]
+    fn invalid_remaps() {
+        assert!("prod/#=staging".parse::<Remap>().is_err());
+        assert!("prod=staging/#".parse::<Remap>().is_err());
+        assert!("prod/#".parse::<Remap>().is_err());
+    }
+
+    /// The due times of the messages logged at `times`.
+    fn due(schedule: &mut Schedule, times: &[f64]) -> Vec<Option<f64>> {
+        times.iter().map(|&time| schedule.advance(time)).collect()
+    }
+
+    #[test]
+    fn realtime_is_accepted() {
+        let opt = Opt::from_iter_safe(["mqtt-replay", "--realtime", "log.json"]).unwrap();
+        assert!(opt.realtime);
+        assert!(!opt.asap);
+
+        assert!(Opt::from_iter_safe(["mqtt-replay", "--realtime", "--asap", "log.json"]).is_err());
+    }
+
+    #[test]
+    fn logged_timing() {
+        let mut schedule = Schedule::new(1., None, 0.);
+        assert_eq!(
+            due(&mut schedule, &[100., 100.5, 110.5]),
+            [None, Some(0.5), Some(10.5)]
+        );
+
+        let mut schedule = Schedule::new(2., None, 0.);
+        assert_eq!(due(&mut schedule, &[100., 110.]), [None, Some(5.)]);
+    }
+
+    #[test]
+    fn max_gap_shortens_long_gaps() {
+        let mut schedule = Schedule::new(1., Some(1.), 0.);
+        assert_eq!(
+            due(&mut schedule, &[100., 100.5, 110.5, 111., 150.]),
+            [None, Some(0.5), Some(1.5), Some(2.), Some(3.)]
+        );
+        assert_eq!(schedule.shortened, 9. + 38.);
+
+        // The gap is compared at the replay speed, and shortened to max_gap at that speed
+        let mut schedule = Schedule::new(2., Some(1.), 0.);
+        assert_eq!(
+            due(&mut schedule, &[100., 101.5, 111.5]),
+            [None, Some(0.75), Some(1.75)]
+        );
+        assert_eq!(schedule.shortened, 8.);
+    }
+
+    #[test]
+    fn max_gap_ignores_gaps_while_seeking() {
+        let mut schedule = Schedule::new(1., Some(1.), 5.);
+        assert_eq!(
+            due(&mut schedule, &[100., 104., 130., 131., 140.]),
+            [None, None, None, Some(1.), Some(2.)]
+        );
+        assert!(schedule.seek_done);
+    }
+}
@@ -0,0 +1,163 @@
+use log::*;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::{LogReader, ReadError};
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+// Reference:
+// {"time": 1611137748.0325797, "qos": 0, "retain": true, "topic": "kvarntorp-test/gateway/165640a7e023861a/nodeversion", "msg_b64": "IjAuMi4xNSI="}
+
+#[derive(Serialize, Debug, Default)]
+struct TopicStats {
+    topic: String,
+    messages: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct Summary {
+    messages: u64,
+    bytes: u64,
+    first_time: Option<f64>,
+    last_time: Option<f64>,
+    duration: f64,
+    messages_per_second: f64,
+    topic_count: usize,
+    top_topics: Vec<TopicStats>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-stats",
+    about = "Summary statistics of a logged MQTT stream"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Input log file
+    #[structopt(env = "INPUT", parse(from_os_str))]
+    input: PathBuf,
+
+    /// Number of topics to show, ordered by message count
+    #[structopt(long, env = "TOP", default_value = "10")]
+    top: usize,
+
+    /// Print the summary as JSON
+    #[structopt(long)]
+    json: bool,
+
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    let input = opt.input;
+    let codec = Codec::of_input(&input, opt.zstd);
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    let log_file = LogReader::new(BufReader::new(codec.decoder(File::open(&input)?)?))?;
+
+    let mut summary = Summary::default();
+    let mut topics: HashMap<String, TopicStats> = HashMap::new();
+
+    for msg in log_file {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(ReadError::Corrupt(e)) => {
+                error!("Corrupted dataset: {}", e);
+                continue;
+            }
+            Err(ReadError::Io(e)) => {
+                error!(
+                    "Unable to read further from the log file, it may be truncated: {}",
+                    e
+                );
+                break;
+            }
+        };
+
+        let bytes = match msg.payload.decode() {
+            Ok(payload) => payload.len() as u64,
+            Err(e) => {
+                error!("Corrupted dataset: payload could not be decoded '{}'", e);
+                0
+            }
+        };
+
+        summary.messages += 1;
+        summary.bytes += bytes;
+        let time = msg.time.secs();
+        summary.first_time = Some(summary.first_time.map_or(time, |t| t.min(time)));
+        summary.last_time = Some(summary.last_time.map_or(time, |t| t.max(time)));
+
+        let topic = topics
+            .entry(msg.topic)
+            .or_insert_with_key(|topic| TopicStats {
+                topic: topic.clone(),
+                ..Default::default()
+            });
+        topic.messages += 1;
+        topic.bytes += bytes;
+    }
+
+    if let (Some(first), Some(last)) = (summary.first_time, summary.last_time) {
+        summary.duration = last - first;
+    }
+
+    if summary.duration > 0.0 {
+        summary.messages_per_second = summary.messages as f64 / summary.duration;
+    }
+
+    let mut topics: Vec<_> = topics.into_values().collect();
+    topics.sort_by(|a, b| b.messages.cmp(&a.messages).then(a.topic.cmp(&b.topic)));
+
+    summary.topic_count = topics.len();
+
+    topics.truncate(opt.top);
+    summary.top_topics = topics;
+
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+
+    println!("Messages:         {}", summary.messages);
+    println!(
+        "Payload size:     {} bytes ({:.2} MB)",
+        summary.bytes,
+        summary.bytes as f64 / 1024. / 1024.
+    );
+    println!("Duration:         {:.1} s", summary.duration);
+    println!("Messages/s:       {:.2}", summary.messages_per_second);
+    println!("Topics:           {}", summary.topic_count);
+    println!();
+    println!("{:>10} {:>12}  Topic", "Messages", "Bytes");
+
+    for topic in &summary.top_topics {
+        println!(
+            "{:>10} {:>12}  {}",
+            topic.messages, topic.bytes, topic.topic
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use anyhow::anyhow;
+use mqtt_logger::codec::Codec;
+use mqtt_logger::reader::{LogReader, ReadError};
+use simple_logger::SimpleLogger;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "mqtt-verify",
+    about = "Checks that every message of a log can be read, exits with an error otherwise"
+)]
+struct Opt {
+    /// The verbosity of output from this program, the higher the more output one can expect
+    #[structopt(short, long, env = "VERBOSITY", default_value = "1")]
+    verbosity: u32,
+
+    /// Input log file
+    #[structopt(env = "INPUT", parse(from_os_str))]
+    input: PathBuf,
+
+    /// The file is a ZSTD compressed log file, or uncompressed with false. Without it the
+    /// compression is told by the extension: .zst, .gz or .lz4
+    #[structopt(long, env = "ZSTD")]
+    zstd: Option<bool>,
+}
+
+/// Why a record could not be read.
+#[derive(Debug, PartialEq)]
+enum Reason {
+    Corrupt(String),
+    Truncated(String),
+    Payload(String),
+}
+
+/// The first record of a log which could not be read.
+#[derive(Debug, PartialEq)]
+struct Failure {
+    /// `line` in JSON logs, which have one record per line, and `record` in MessagePack logs
+    record: &'static str,
+    /// The number of the line or record, counting from 1
+    number: u64,
+    reason: Reason,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.reason {
+            Reason::Corrupt(e) => write!(f, "Corrupted {} {}: {}", self.record, self.number, e),
+            Reason::Truncated(e) => write!(
+                f,
+                "Unable to read {} {}, the log file may be truncated: {}",
+                self.record, self.number, e
+            ),
+            Reason::Payload(e) => write!(
+                f,
+                "The payload of {} {} could not be decoded: {}",
+                self.record, self.number, e
+            ),
+        }
+    }
+}
+
+/// Reads every message of the log, returning their number or the first which can not be read.
+fn verify<R: BufRead>(log_file: &mut LogReader<R>) -> Result<u64, Failure> {
+    let record = match log_file {
+        LogReader::Json(..) => "line",
+        LogReader::MsgPack(_) => "record",
+    };
+
+    let mut messages = 0u64;
+    while let Some(msg) = log_file.next() {
+        let number = log_file.line().unwrap_or(messages + 1);
+        let failure = |reason| Failure {
+            record,
+            number,
+            reason,
+        };
+
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(ReadError::Corrupt(e)) => return Err(failure(Reason::Corrupt(e))),
+            Err(ReadError::Io(e)) => return Err(failure(Reason::Truncated(e.to_string()))),
+        };
+
+        if let Err(e) = msg.payload.decode() {
+            return Err(failure(Reason::Payload(e.to_string())));
+        }
+
+        messages += 1;
+    }
+
+    Ok(messages)
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Opt::from_args();
+
+    let input = opt.input;
+    let codec = Codec::of_input(&input, opt.zstd);
+
+    match opt.verbosity {
+        0 => SimpleLogger::new().with_level(log::LevelFilter::Off),
+        1 => SimpleLogger::new().with_level(log::LevelFilter::Error),
+        2 => SimpleLogger::new().with_level(log::LevelFilter::Info),
+        3 => SimpleLogger::new().with_level(log::LevelFilter::Debug),
+        _ => SimpleLogger::new().with_level(log::LevelFilter::Trace),
+    }
+    .init()?;
+
+    let mut log_file = LogReader::new(BufReader::new(codec.decoder(File::open(&input)?)?))?;
+    let messages = verify(&mut log_file).map_err(|failure| anyhow!("{}", failure))?;
+
+    println!("OK, {} messages", messages);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    const GOOD: &str = r#"{"time":1.0,"qos":0,"retain":false,"topic":"a","msg_b64":"YWJj"}"#;
+
+    fn verify_log(lines: &[&str]) -> Result<u64, Failure> {
+        let log = lines.join("\n") + "\n";
+        verify(&mut LogReader::new(Cursor::new(log)).unwrap())
+    }
+
+    #[test]
+    fn readable_logs() {
+        assert_eq!(verify_log(&[GOOD, GOOD, GOOD]), Ok(3));
+    }
+
+    #[test]
+    fn bad_base64() {
+        let bad = GOOD.replace("YWJj", "YW*j");
+        let failure = verify_log(&[GOOD, GOOD, &bad, GOOD]).unwrap_err();
+
+        assert_eq!(failure.number, 3);
+        assert!(matches!(failure.reason, Reason::Payload(_)));
+        assert!(failure
+            .to_string()
+            .starts_with("The payload of line 3 could not be decoded"));
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let failure = verify_log(&[GOOD, r#"{"time":1.0,"qos":"#, GOOD]).unwrap_err();
+
+        assert_eq!(failure.number, 2);
+        assert!(matches!(failure.reason, Reason::Corrupt(_)));
+        assert!(failure.to_string().starts_with("Corrupted line 2: "));
+    }
+
+    /// A stream which can not be read further, as of a truncated ZSTD frame.
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "incomplete frame",
+            ))
+        }
+    }
+
+    #[test]
+    fn unreadable_stream() {
+        let log = Cursor::new(format!("{}\n", GOOD)).chain(Broken);
+        let failure = verify(&mut LogReader::new(BufReader::new(log)).unwrap()).unwrap_err();
+
+        assert_eq!(failure.number, 2);
+        assert!(matches!(failure.reason, Reason::Truncated(_)));
+    }
+}
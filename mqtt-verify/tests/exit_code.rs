@@ -0,0 +1,44 @@
+//! Scripts check logs by the exit code, which fails at the first unreadable record.
+
+use std::process::Command;
+
+const GOOD: &str = r#"{"time":1.0,"qos":0,"retain":false,"topic":"a","msg_b64":"YWJj"}"#;
+
+/// Verifies an uncompressed log of `lines`, returning whether it passed and the output.
+fn verify(name: &str, lines: &[&str]) -> (bool, String) {
+    let path =
+        std::env::temp_dir().join(format!("mqtt-verify-{}-{}.json", std::process::id(), name));
+    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mqtt-verify"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut text = String::from_utf8(output.stdout).unwrap();
+    text += &String::from_utf8(output.stderr).unwrap();
+
+    (output.status.success(), text)
+}
+
+#[test]
+fn readable_log_passes() {
+    let (success, output) = verify("good", &[GOOD, GOOD]);
+
+    assert!(success);
+    assert!(output.contains("OK, 2 messages"), "{}", output);
+}
+
+#[test]
+fn bad_payload_fails() {
+    let bad = GOOD.replace("YWJj", "YW*j");
+    let (success, output) = verify("bad", &[GOOD, &bad]);
+
+    assert!(!success);
+    assert!(
+        output.contains("The payload of line 2 could not be decoded"),
+        "{}",
+        output
+    );
+}